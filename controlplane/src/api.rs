@@ -2,12 +2,17 @@
 // GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
 
 mod database;
+mod ipam;
+mod liveness;
 mod ovn;
+mod scheduler;
 
 use axum::{
     extract::Json, http::StatusCode, response::IntoResponse, routing::{get,post}, Router
 };
 use ovn::{delete_dhcpv4_options, extract_uuid_from_response, get_dhcpv4_options_id, remove_lsp};
+use ovn::{dns_domain, create_dns_table, set_dns_record, remove_dns_record, get_lsp_dynamic_addresses, get_lsp_mac_address};
+use ovn::{provider_switch_name, create_provider_switch, delete_provider_switch, add_static_address_to_lsp, set_lsp_qos, set_lsp_requested_chassis};
 use sqlx::{prelude::FromRow, types::ipnetwork::IpNetwork};
 use tower_http::trace::{TraceLayer, DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse};
 use sqlx::types::Uuid;
@@ -24,6 +29,11 @@ use axum::http::Method;
 use ssh_key::PublicKey;
 
 use std::collections::HashSet;
+use chrono::{DateTime, Utc};
+use axum::{body::Body, extract::{Path, Query}};
+use axum::http::HeaderMap;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
 
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
@@ -73,9 +83,14 @@ pub struct HypervisorAgent {
     cpu: i32,
     arch: String,
     vms: Vec<HypervisorSchedulerVM>,
+    #[serde(default)]
+    images: Vec<String>,
+    cpu_model: Option<String>,
+    availability_zone: Option<String>,
+    labels: Option<Vec<String>>,
 }
 
-#[derive(FromRow, serde::Serialize, serde::Deserialize)]
+#[derive(FromRow, serde::Serialize, serde::Deserialize, Clone)]
 pub struct HypervisorScheduler {
     id: Uuid,
     hostname: String,
@@ -85,6 +100,68 @@ pub struct HypervisorScheduler {
     used_cpu: i32,
     hosted_vms: i32,
     arch: String,
+    cached_images: Vec<String>,
+    maintenance: bool,
+    last_seen: DateTime<Utc>,
+    status: String,
+    cpu_allocation_ratio: Option<f64>,
+    ram_allocation_ratio: Option<f64>,
+    reserved_cpu: Option<i32>,
+    reserved_ram: Option<i32>,
+    cpu_model: Option<String>,
+    availability_zone: Option<String>,
+    labels: Vec<String>,
+    #[sqlx(default)]
+    claimed_cpu: i32,
+    #[sqlx(default)]
+    claimed_ram: i32,
+}
+
+// Hypervisors that set a zone or labels in their agent configuration get
+// them back on their next report.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HypervisorLabels {
+    hostname: String,
+    availability_zone: Option<String>,
+    labels: Option<Vec<String>>,
+}
+
+// Labels are comma separated, hypervisors must carry all of them.
+#[derive(serde::Deserialize)]
+pub struct HypervisorListQuery {
+    availability_zone: Option<String>,
+    labels: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HypervisorMaintenance {
+    hostname: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HypervisorEvacuate {
+    hostname: String,
+    #[serde(default)]
+    from_image: bool,
+}
+
+#[derive(serde::Serialize)]
+pub struct VmOutcome {
+    name: String,
+    tenant: String,
+    outcome: String,
+    hypervisor: Option<String>,
+    error: Option<String>,
+}
+
+// Unset values fall back to the scheduler overcommit defaults.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HypervisorOvercommit {
+    hostname: String,
+    cpu_allocation_ratio: Option<f64>,
+    ram_allocation_ratio: Option<f64>,
+    reserved_cpu: Option<i32>,
+    reserved_ram: Option<i32>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
@@ -98,6 +175,7 @@ pub struct HypervisorSchedulerVM {
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow, Debug)]
 pub struct VirtualMachine {
+    id: Uuid,
     name: String,
     ram: i32,
     cpu: i32,
@@ -108,22 +186,65 @@ pub struct VirtualMachine {
     ssh_pub_key: Uuid,
     tenant: Uuid,
     hypervisor: Uuid,
-    ip_addresses: Vec<IpNetwork>
+    networking: String,
+    network: Option<String>,
+    ip_addresses: Vec<IpNetwork>,
+    static_ip: Option<IpNetwork>,
+    ingress_kbps: Option<i32>,
+    egress_kbps: Option<i32>,
+    burst_kbit: Option<i32>,
+    image: Option<Uuid>,
+    base_image: Option<String>,
+    flavor: Option<Uuid>,
+    server_group: Option<Uuid>,
+    scheduling_attempts: Vec<String>,
+    mac_addr: Option<String>,
+    migration_source: Option<Uuid>,
+    availability_zone: Option<String>,
+    label_selector: Vec<String>
 }
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
 pub struct VirtualMachineCreate {
     name: String,
-    ram: i32,
-    cpu: i32,
+    flavor: Option<String>,
+    ram: Option<i32>,
+    cpu: Option<i32>,
     os: String,
-    disk_size: i32,
+    disk_size: Option<i32>,
     vpc: String,
     ssh_pub_key: String,
     tenant: String,
     arch: String,
     networking: String,
-    network: Option<String>
+    network: Option<String>,
+    image_version: Option<String>,
+    server_group: Option<String>,
+    availability_zone: Option<String>,
+    #[serde(default)]
+    #[sqlx(default)]
+    label_selector: Vec<String>,
+    #[serde(default)]
+    #[sqlx(default)]
+    copy_on_write: bool,
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    qos: VirtualMachineQos
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct VirtualMachineQos {
+    ingress_kbps: Option<i32>,
+    egress_kbps: Option<i32>,
+    burst_kbit: Option<i32>
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VirtualMachineQosUpdate {
+    name: String,
+    tenant: String,
+    #[serde(flatten)]
+    qos: VirtualMachineQos
 }
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
@@ -132,6 +253,40 @@ pub struct VirtualMachineDelete {
     tenant: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VirtualMachineMigrate {
+    name: String,
+    tenant: String,
+    #[serde(flatten)]
+    options: MigrationOptions,
+}
+
+// Without a hypervisor the scheduler picks the target. Bandwidth (MiB/s) and
+// auto-converge only apply to live migrations.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct MigrationOptions {
+    hypervisor: Option<String>,
+    bandwidth: Option<u64>,
+    #[serde(default)]
+    auto_converge: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VirtualMachineResize {
+    name: String,
+    tenant: String,
+    flavor: Option<String>,
+    cpu: Option<i32>,
+    ram: Option<i32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VirtualMachineDiskResize {
+    name: String,
+    tenant: String,
+    disk_size: i32,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
 pub struct SSHKey {
     name: Option<String>,
@@ -144,7 +299,13 @@ pub struct SSHKey {
 pub struct ProviderNetwork {
     name: String,
     vlan: i32,
-    subnet: String
+    subnet: String,
+    gateway: Option<IpNetwork>,
+    allocation_start: Option<IpNetwork>,
+    allocation_end: Option<IpNetwork>,
+    #[serde(default)]
+    dns_servers: Vec<IpNetwork>,
+    dhcp: Option<bool>
 }
 
 #[derive(serde::Serialize, serde::Deserialize, FromRow)]
@@ -152,6 +313,498 @@ pub struct ProviderNetworkDelete {
     name: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct Volume {
+    id: Uuid,
+    name: String,
+    tenant: Uuid,
+    size: i32,
+    hypervisor: Uuid,
+    state: String,
+    vm: Option<Uuid>,
+    target_dev: Option<String>,
+    serial: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageStoreConfig {
+    images: ImageStore,
+}
+
+#[derive(serde::Deserialize)]
+struct ImageStore {
+    path: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct Image {
+    id: Uuid,
+    name: String,
+    version: String,
+    arch: String,
+    format: String,
+    checksum: String,
+    min_disk: i32,
+    min_ram: i32,
+    default_user: String,
+    cloud_init: bool,
+    owner: Option<Uuid>,
+    visibility: String,
+    file: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageCreate {
+    name: String,
+    version: String,
+    arch: String,
+    format: String,
+    checksum: String,
+    min_disk: i32,
+    min_ram: i32,
+    default_user: String,
+    cloud_init: Option<bool>,
+    visibility: Option<String>,
+    #[serde(skip)]
+    owner: Option<Uuid>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageCapture {
+    vm: String,
+    tenant: String,
+    name: String,
+    version: String,
+    visibility: Option<String>,
+    #[serde(default)]
+    sysprep: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageListQuery {
+    tenant: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct ServerGroup {
+    id: Uuid,
+    name: String,
+    tenant: Uuid,
+    policy: String,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ServerGroupCreate {
+    name: String,
+    tenant: String,
+    policy: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ServerGroupRequest {
+    name: String,
+    tenant: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct Flavor {
+    id: Uuid,
+    name: String,
+    cpu: i32,
+    ram: i32,
+    disk_size: i32,
+    ingress_kbps: Option<i32>,
+    egress_kbps: Option<i32>,
+    burst_kbit: Option<i32>,
+    archs: Vec<String>,
+    hugepages: bool,
+    cpu_pinning: bool,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FlavorCreate {
+    name: String,
+    cpu: i32,
+    ram: i32,
+    disk_size: i32,
+    #[serde(flatten)]
+    qos: VirtualMachineQos,
+    archs: Option<Vec<String>>,
+    #[serde(default)]
+    hugepages: bool,
+    #[serde(default)]
+    cpu_pinning: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct FlavorDelete {
+    name: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow, Default)]
+pub struct Quota {
+    vms: Option<i32>,
+    vcpus: Option<i32>,
+    ram: Option<i32>,
+    disk: Option<i32>,
+    volumes: Option<i32>,
+    vpcs: Option<i32>,
+    floating_ips: Option<i32>,
+    snapshots: Option<i32>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct QuotaUpdate {
+    tenant: String,
+    #[serde(flatten)]
+    quota: Quota,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow, Default)]
+pub struct ResourceUsage {
+    vms: i64,
+    vcpus: i64,
+    ram: i64,
+    disk: i64,
+    volumes: i64,
+    vpcs: i64,
+    floating_ips: i64,
+    snapshots: i64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct QuotaQuery {
+    tenant: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AdminConfig {
+    controlplane: AdminToken,
+}
+
+#[derive(serde::Deserialize)]
+struct AdminToken {
+    admin_token: Option<String>,
+    agent_token: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ImageDelete {
+    name: String,
+    version: String,
+    arch: String,
+    tenant: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, FromRow)]
+pub struct Snapshot {
+    id: Uuid,
+    name: String,
+    tenant: Uuid,
+    vm: Uuid,
+    kind: String,
+    quiesced: bool,
+    disks: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SnapshotCreate {
+    name: String,
+    tenant: String,
+    vm: String,
+    kind: Option<String>,
+    #[serde(default)]
+    quiesce: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SnapshotRequest {
+    name: String,
+    tenant: String,
+    vm: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct SnapshotList {
+    tenant: String,
+    vm: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VolumeCreate {
+    name: String,
+    tenant: String,
+    size: i32,
+    hypervisor: Option<String>,
+    vm: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VolumeResize {
+    name: String,
+    tenant: String,
+    size: i32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VolumeRequest {
+    name: String,
+    tenant: String,
+    vm: Option<String>,
+}
+
+// Base images are looked up by file name on the hypervisors, one file per
+// catalog entry.
+fn image_file_name(name: &str, version: &str, arch: &str, format: &str) -> String {
+    format!("{}-{}-{}.{}", name, version, arch, format)
+}
+
+// Names that end up in file paths or libvirt XML on the hypervisors.
+fn is_valid_name(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_')
+}
+
+fn validate_image_name(name: &str, version: &str) -> Result<(), String> {
+    if !is_valid_name(name) || !is_valid_name(version) {
+        return Err("Image name and version may only contain letters, digits, '.' and '_'.".to_string());
+    }
+
+    Ok(())
+}
+
+fn validate_image_visibility(visibility: Option<&str>) -> Result<(), String> {
+    match visibility {
+        None | Some("public") | Some("private") => Ok(()),
+        _ => Err("Invalid image visibility specified. Only 'public' and 'private' are supported.".to_string()),
+    }
+}
+
+// Labels are free form ("gpu", "disk=ssd") but are listed comma separated.
+fn validate_labels(labels: &[String]) -> Result<(), String> {
+    match labels.iter().find(|label| label.is_empty() || label.contains(',') || label.contains(char::is_whitespace)) {
+        Some(label) => Err(format!("Invalid label '{}', labels cannot be empty or contain commas or whitespace.", label)),
+        None => Ok(()),
+    }
+}
+
+impl Image {
+    fn file_name(&self) -> String {
+        match &self.file {
+            Some(file) => file.clone(),
+            None => image_file_name(&self.name, &self.version, &self.arch, &self.format),
+        }
+    }
+}
+
+impl ImageCreate {
+    fn validate(&self) -> Result<(), String> {
+        validate_image_name(&self.name, &self.version)?;
+        validate_image_visibility(self.visibility.as_deref())?;
+
+        if !["x86_64", "aarch64"].contains(&self.arch.as_str()) {
+            return Err("Invalid image arch specified. Only 'x86_64' and 'aarch64' are supported.".to_string());
+        }
+
+        if !["qcow2", "raw"].contains(&self.format.as_str()) {
+            return Err("Invalid image format specified. Only 'qcow2' and 'raw' are supported.".to_string());
+        }
+
+        let is_valid_checksum = match self.checksum.split_once(':') {
+            Some(("sha256", digest)) => digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()),
+            Some(("sha512", digest)) => digest.len() == 128 && digest.chars().all(|c| c.is_ascii_hexdigit()),
+            _ => false,
+        };
+        if !is_valid_checksum {
+            return Err("Image checksum must be in the form 'sha256:<hex>' or 'sha512:<hex>'.".to_string());
+        }
+
+        if self.min_disk <= 0 || self.min_ram <= 0 {
+            return Err("Image minimum disk and RAM must be positive.".to_string());
+        }
+
+        if self.default_user.trim().is_empty() {
+            return Err("Image default user must not be empty.".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+impl Flavor {
+    fn qos(&self) -> VirtualMachineQos {
+        VirtualMachineQos {
+            ingress_kbps: self.ingress_kbps,
+            egress_kbps: self.egress_kbps,
+            burst_kbit: self.burst_kbit,
+        }
+    }
+}
+
+impl FlavorCreate {
+    fn archs(&self) -> Vec<String> {
+        self.archs.clone().unwrap_or(vec!["x86_64".to_string(), "aarch64".to_string()])
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || !self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+            return Err("Flavor name may only contain letters, digits, '.' and '_'.".to_string());
+        }
+
+        if self.cpu <= 0 || self.ram <= 0 || self.disk_size <= 0 {
+            return Err("Flavor cpu, ram and disk_size must be positive values.".to_string());
+        }
+
+        let archs = ["x86_64", "aarch64"];
+        if self.archs().is_empty() || !self.archs().iter().all(|arch| archs.contains(&arch.as_str())) {
+            return Err(format!("Invalid flavor archs specified. Only {} are supported.", archs.join(" and ")));
+        }
+
+        self.qos.validate()
+    }
+}
+
+// Admin only operations are authorized by the token configured for the
+// controlplane, sent in the X-AWP-Admin-Token header. Without a configured
+// token nobody is an admin. It is read once, changing it needs a restart.
+static ADMIN_TOKEN: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+fn is_admin(headers: &HeaderMap) -> bool {
+    let admin_token = ADMIN_TOKEN.get_or_init(|| {
+        std::fs::read_to_string("config.yaml").ok()
+            .and_then(|config| serde_yaml::from_str::<AdminConfig>(&config).ok())
+            .and_then(|config| config.controlplane.admin_token)
+            .filter(|admin_token| !admin_token.is_empty())
+    });
+
+    match (admin_token, headers.get("X-AWP-Admin-Token").and_then(|token| token.to_str().ok())) {
+        (Some(admin_token), Some(token)) => admin_token == token,
+        _ => false,
+    }
+}
+
+// Hypervisors share the agent token of their migration config with the
+// controlplane, it is sent in the X-AWP-Agent-Token header both ways.
+static AGENT_TOKEN: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+
+fn agent_token() -> Option<&'static str> {
+    AGENT_TOKEN.get_or_init(|| {
+        std::fs::read_to_string("config.yaml").ok()
+            .and_then(|config| serde_yaml::from_str::<AdminConfig>(&config).ok())
+            .and_then(|config| config.controlplane.agent_token)
+            .filter(|agent_token| !agent_token.is_empty())
+    }).as_deref()
+}
+
+fn is_agent(headers: &HeaderMap) -> bool {
+    match (agent_token(), headers.get("X-AWP-Agent-Token").and_then(|token| token.to_str().ok())) {
+        (Some(agent_token), Some(token)) => agent_token == token,
+        _ => false,
+    }
+}
+
+impl Quota {
+    // Limits left unset are unlimited, requests only ever add to the usage.
+    fn check(&self, usage: &ResourceUsage, requested: &ResourceUsage) -> Result<(), String> {
+        let resources = [
+            ("VMs", self.vms, usage.vms, requested.vms),
+            ("vCPUs", self.vcpus, usage.vcpus, requested.vcpus),
+            ("RAM (G)", self.ram, usage.ram, requested.ram),
+            ("disk (G)", self.disk, usage.disk, requested.disk),
+            ("volumes", self.volumes, usage.volumes, requested.volumes),
+            ("VPCs", self.vpcs, usage.vpcs, requested.vpcs),
+            ("floating IPs", self.floating_ips, usage.floating_ips, requested.floating_ips),
+            ("snapshots", self.snapshots, usage.snapshots, requested.snapshots),
+        ];
+
+        for (name, limit, used, requested) in resources {
+            if let Some(limit) = limit {
+                if requested > 0 && used + requested > i64::from(limit) {
+                    return Err(format!("Quota exceeded for {}: {} of {} in use, {} more requested.", name, used, limit, requested));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Takes the tenant quota row lock for the rest of the transaction, concurrent
+// requests of the same tenant are checked one after the other as long as the
+// resources are recorded before it commits.
+async fn check_quota(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, tenant: &Uuid, requested: &ResourceUsage) -> Result<(), (StatusCode, String)> {
+    let quota = Database::lock_quota(tx, tenant).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+    let usage = Database::get_resource_usage(&mut **tx, tenant).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+
+    quota.check(&usage, requested).map_err(|e| (StatusCode::FORBIDDEN, e))
+}
+
+// Points the VM record at the hypervisor about to be tried. Members placed by
+// concurrent requests since the scheduling decision are only visible under the
+// group lock, hard policies are checked again.
+async fn place_vm(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    name: &str,
+    tenant: &Uuid,
+    server_group: Option<&ServerGroup>,
+    hypervisor: &HypervisorScheduler,
+    scheduler_config: &scheduler::SchedulerConfig,
+    request_spec: &scheduler::RequestSpec<'_>
+) -> Result<(), (StatusCode, String)> {
+    // Capacity is claimed under the hypervisor row lock, concurrent requests
+    // see each other's claims until the agent reports the VMs as running.
+    // The claim is sized after the request, a resize migration places the VM
+    // before its record is updated to the new size.
+    let hypervisor = match Database::lock_hypervisor(tx, &hypervisor.id).await {
+        Ok(Some(hypervisor)) => hypervisor,
+        Ok(None) => return Err((StatusCode::CONFLICT, format!("Hypervisor '{}' is gone.", &hypervisor.hostname))),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))),
+    };
+
+    if !scheduler::capacity(scheduler_config, &hypervisor).fits(&hypervisor, request_spec.cpu, request_spec.ram) {
+        return Err((StatusCode::CONFLICT, format!("Hypervisor '{}' no longer has the resources to host the VM.", &hypervisor.hostname)));
+    }
+
+    if let Some(server_group) = server_group {
+        let group_hypervisors = Database::lock_server_group(tx, &server_group.id, name).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)))?;
+
+        if !scheduler::policy_allows(&server_group.policy, &group_hypervisors, &hypervisor.id) {
+            return Err((StatusCode::CONFLICT, format!("Hypervisor '{}' no longer satisfies the '{}' policy of server group '{}', please retry.", &hypervisor.hostname, &server_group.policy, &server_group.name)));
+        }
+
+        Database::set_vm_server_group(&mut **tx, name, tenant, &server_group.id).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add VM to server group: {}", e)))?;
+    }
+
+    Database::update_vm_hypervisor(&mut **tx, name, tenant, &hypervisor.id).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)))?;
+
+    Database::claim_capacity(&mut **tx, name, tenant, &hypervisor.id, &request_spec.cpu, &request_spec.ram).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to claim hypervisor capacity: {}", e)))
+}
+
+impl VirtualMachineQos {
+    fn is_empty(&self) -> bool {
+        self.ingress_kbps.is_none() && self.egress_kbps.is_none() && self.burst_kbit.is_none()
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("ingress_kbps", self.ingress_kbps), ("egress_kbps", self.egress_kbps), ("burst_kbit", self.burst_kbit)] {
+            if value.is_some_and(|value| value <= 0) {
+                return Err(format!("QoS '{}' must be a positive value.", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct ControlPlaneAPI {}
 
 impl ControlPlaneAPI {
@@ -175,12 +828,47 @@ impl ControlPlaneAPI {
             .route("/ports/list", get(list_ports_handler))
             .route("/hypervisor/stats", post(hypervisor_stats_handler))
             .route("/hypervisors/list", get(list_hypervisors_handler))
+            .route("/hypervisor/overcommit", post(hypervisor_overcommit_handler))
+            .route("/hypervisor/labels", post(hypervisor_labels_handler))
+            .route("/hypervisor/disable", post(disable_hypervisor_handler))
+            .route("/hypervisor/enable", post(enable_hypervisor_handler))
+            .route("/hypervisor/drain", post(drain_hypervisor_handler))
+            .route("/hypervisor/evacuate", post(evacuate_hypervisor_handler))
             .route("/ssh_pub_key/create", post(create_ssh_pub_key))
             .route("/ssh_pub_key/delete", post(delete_ssh_pub_key))
             .route("/ssh_pub_keys/list", post(list_ssh_pub_keys))
             .route("/virtualmachine/create", post(virtual_machine_scheduler))
             .route("/virtualmachine/delete", post(delete_vm_handler))
             .route("/virtualmachines/list", post(list_vm_handler))
+            .route("/virtualmachine/qos", post(qos_vm_handler))
+            .route("/virtualmachine/flatten", post(flatten_vm_handler))
+            .route("/virtualmachine/resize", post(resize_vm_handler))
+            .route("/virtualmachine/resize_disk", post(resize_vm_disk_handler))
+            .route("/virtualmachine/migrate", post(migrate_vm_handler))
+            .route("/virtualmachine/migration", post(migration_progress_handler))
+            .route("/volume/create", post(create_volume_handler))
+            .route("/volume/delete", post(delete_volume_handler))
+            .route("/volume/resize", post(resize_volume_handler))
+            .route("/volume/attach", post(attach_volume_handler))
+            .route("/volume/detach", post(detach_volume_handler))
+            .route("/volumes/list", post(list_volumes_handler))
+            .route("/snapshot/create", post(create_snapshot_handler))
+            .route("/snapshot/revert", post(revert_snapshot_handler))
+            .route("/snapshot/delete", post(delete_snapshot_handler))
+            .route("/snapshots/list", post(list_snapshots_handler))
+            .route("/quota/set", post(set_quota_handler))
+            .route("/quotas/usage", get(quota_usage_handler))
+            .route("/servergroup/create", post(create_server_group_handler))
+            .route("/servergroup/delete", post(delete_server_group_handler))
+            .route("/servergroups/list", post(list_server_groups_handler))
+            .route("/flavor/create", post(create_flavor_handler))
+            .route("/flavor/delete", post(delete_flavor_handler))
+            .route("/flavors/list", get(list_flavors_handler))
+            .route("/image/create", post(create_image_handler))
+            .route("/image/delete", post(delete_image_handler))
+            .route("/image/capture", post(capture_image_handler))
+            .route("/images/list", get(list_images_handler))
+            .route("/images/download/:file", get(download_image_handler))
             .route("/provider_network/create", post(create_provider_network_handler))
             .route("/provider_network/delete", post(delete_provider_network_handler))
             .route("/provider_networks/list", get(list_provider_networks_handler))
@@ -194,6 +882,22 @@ impl ControlPlaneAPI {
             .layer(cors)
     }
 
+    pub async fn monitor_hypervisors() {
+        let config = liveness::read_conf_file("config.yaml").unwrap_or_else(|e| {
+            eprintln!("Failed to read liveness configuration, using defaults: {}", e);
+            liveness::LivenessConfig::default()
+        });
+
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(config.check_interval));
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = liveness::check_hypervisors(&config).await {
+                eprintln!("Failed to check hypervisors liveness: {}", e);
+            }
+        }
+    }
+
     pub async fn start_server(app: Router) -> Result<(), Box<dyn std::error::Error>> {
         let listener = match tokio::net::TcpListener::bind("0.0.0.0:8080").await {
             Ok(listener) => {
@@ -367,30 +1071,66 @@ async fn create_vpc_handler(Json(payload): Json<Vpc>) -> impl IntoResponse {
                 return (StatusCode::BAD_REQUEST, format!("VPC '{}' already exists.", &name)).into_response();
             }
 
-            let switch_name = format!("{}-{}", &tenant, &name);
-            let l2_switch = create_l2_switch(&switch_name, &cidr).await;
-            match l2_switch {
-                Ok(_) => (),
-                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create L2 switch: {}", e)).into_response(),
-            }
-
-            match create_dhcpv4_options(&cidr).await {
-                Ok(_) => (),
-                Err(e) => {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create DHCPv4 options: {}", e)).into_response();
-                }
+            let tenant_name = match Database::get_tenant_by_id(&db, &tenant).await {
+                Ok(Some(tenant_name)) => tenant_name,
+                Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            };
+
+            let mut tx = match db.begin().await {
+                Ok(tx) => tx,
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            };
+
+            if let Err(e) = check_quota(&mut tx, &tenant, &ResourceUsage { vpcs: 1, ..Default::default() }).await {
+                return e.into_response();
+            }
+
+            // The record counts against the quota once committed, the quota
+            // lock is not held while OVN is set up.
+            let vpc_uuid = match Database::create_vpc(&mut *tx, &name, &cidr, &nat, &tenant).await {
+                Ok(vpc_uuid) => vpc_uuid,
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VPC: {}", e)).into_response(),
+            };
+
+            if let Err(e) = tx.commit().await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VPC: {}", e)).into_response();
+            }
+
+            let switch_name = format!("{}-{}", &tenant, &name);
+            if let Err(e) = create_l2_switch(&switch_name, &cidr).await {
+                discard_vpc(&db, &vpc_uuid, &switch_name).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create L2 switch: {}", e)).into_response();
+            }
+
+            let domain = dns_domain(&name, &tenant_name);
+            if let Err(e) = create_dns_table(&switch_name, &domain).await {
+                discard_vpc(&db, &vpc_uuid, &switch_name).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create DNS table: {}", e)).into_response();
             }
 
-            let create_vpc: Result<(), sqlx::Error> = Database::create_vpc(&db, &name, &cidr, &nat, &tenant).await;
-            match create_vpc {
-                Ok(_) => (StatusCode::OK, format!("VPC '{}' created successfully.", name)).into_response(),
-                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VPC: {}", e)).into_response(),
+            if let Err(e) = create_dhcpv4_options(&cidr, &domain).await {
+                discard_vpc(&db, &vpc_uuid, &switch_name).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create DHCPv4 options: {}", e)).into_response();
             }
+
+            (StatusCode::OK, format!("VPC '{}' created successfully.", name)).into_response()
         },
         _ => (StatusCode::BAD_REQUEST, format!("VPC create request must include a name and CIDR.")).into_response(),
     }
 }
 
+// Deleting the switch also drops its DNS table.
+async fn discard_vpc(db: &sqlx::Pool<sqlx::Postgres>, id: &Uuid, switch_name: &str) {
+    if let Err(e) = ovn::delete_l2_switch(switch_name).await {
+        eprintln!("Failed to remove L2 switch '{}': {}", switch_name, e);
+    }
+
+    if let Err(e) = Database::delete_vpc(db, id).await {
+        eprintln!("Failed to remove VPC '{}' from database: {}", id, e);
+    }
+}
+
 async fn delete_vpc_handler(Json(payload): Json<VpcDelete>) -> impl IntoResponse {
     let db = match Database::new().await {
         Ok(db) => db,
@@ -517,27 +1257,36 @@ async fn hypervisor_stats_handler(Json(payload): Json<HypervisorAgent>) -> impl
         ).into_response();
     }
     
-    let mut used_ram: i32 = 0;
-    let mut used_cpu: i32 = 0;
-
-    match payload.vms.len() {
-        0 => {
-            used_ram = 1;
-            used_cpu = 1;
-        }
-        _ => {
-            for vm in payload.vms.iter() {
-                used_ram += 1 + vm.memory;
-                used_cpu += 1 + vm.cpu;
-            }
-        }
-    }
+    // Host overhead is accounted for by the reserved resources of the
+    // hypervisor, only what the VMs were given counts as used.
+    let used_ram: i32 = payload.vms.iter().map(|vm| vm.memory).sum();
+    let used_cpu: i32 = payload.vms.iter().map(|vm| vm.cpu).sum();
+
+    // Invalid labels are left out rather than failing the stats, the
+    // hypervisor would look down otherwise.
+    let labels = match payload.labels.as_deref().map(validate_labels) {
+        Some(Err(e)) => {
+            eprintln!("Ignoring labels reported by hypervisor '{}': {}", &payload.hostname, e);
+            None
+        },
+        _ => payload.labels.as_deref(),
+    };
 
     match Database::get_hypervisor_by_hostname(&db, &payload.hostname).await {
         Ok(Some(id)) => {
             if let Err(e) = Database::update_hypervisor(&db, &id, &used_ram, &used_cpu, payload.vms.len() as i32).await {
                 return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response();
             }
+
+            if let Err(e) = Database::update_hypervisor_inventory(&db, &payload, labels).await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor inventory: {}", e)).into_response();
+            }
+
+            // VMs the agent reports are part of the used resources from now on.
+            let domains: Vec<String> = payload.vms.iter().map(|vm| vm.name.clone()).collect();
+            if let Err(e) = Database::reconcile_capacity_claims(&db, &id, &domains).await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to reconcile capacity claims: {}", e)).into_response();
+            }
             
             let mut errors = Vec::new();
             for vm in &payload.vms {
@@ -556,6 +1305,21 @@ async fn hypervisor_stats_handler(Json(payload): Json<HypervisorAgent>) -> impl
                         match Database::get_virtual_machine_by_name(&db, &name, &tenant_uuid).await {
                             Ok(vm_on_db) => {
                                 match vm_on_db {
+                                    // Both ends report the VM while its disk is being copied.
+                                    Some(vm_on_db) if vm_on_db.state == "migrating" => {},
+                                    // Domains left behind on a hypervisor the VM was evacuated
+                                    // from must not overwrite the state of the VM elsewhere,
+                                    // they are dropped while the disk they may share is kept.
+                                    Some(vm_on_db) if vm_on_db.hypervisor != id => {
+                                        let undefine_vm_query = json!({
+                                            "name": name,
+                                            "tenant": tenant,
+                                        });
+
+                                        if let Err(e) = post_to_hypervisor(&payload.hostname, "/virtualmachine/undefine", &undefine_vm_query).await {
+                                            errors.push(format!("Failed to remove stale domain of VM '{}': {}", name, e));
+                                        }
+                                    },
                                     Some(vm_on_db) => {
                                         if vm_on_db.state != agent_state {
                                             if let Err(e) = Database::update_vm_state(&db, &name, &tenant_uuid, &agent_state.to_lowercase()).await {
@@ -567,6 +1331,17 @@ async fn hypervisor_stats_handler(Json(payload): Json<HypervisorAgent>) -> impl
                                             if let Err(e) = Database::update_vm_ip_addr(&db, &name, &tenant_uuid, &vm.ip_addresses).await {
                                                 errors.push(format!("Failed to update VM '{}' IP addresses: {}", name, e));
                                             }
+
+                                            if vm_on_db.networking == "l2-tenant" {
+                                                if let Ok(Some(vpc)) = Database::get_vpc_by_id(&db, &vm_on_db.vpc, &tenant_uuid).await {
+                                                    let ls_name = format!("{}-{}", &tenant_uuid, &vpc);
+                                                    let fqdn = format!("{}.{}", &name, dns_domain(&vpc, tenant));
+                                                    let ips: Vec<String> = vm.ip_addresses.iter().map(|ip| ip.ip().to_string()).collect();
+                                                    if let Err(e) = set_dns_record(&ls_name, &fqdn, &ips).await {
+                                                        errors.push(format!("Failed to update DNS record for VM '{}': {}", name, e));
+                                                    }
+                                                }
+                                            }
                                         }
                                     },
                                     None => {}
@@ -596,7 +1371,10 @@ async fn hypervisor_stats_handler(Json(payload): Json<HypervisorAgent>) -> impl
         Ok(None) => {
             match Database::hypervisor_register(&db, &payload.hostname, &payload.memory, &payload.cpu,
                                               used_ram, used_cpu, &arch, payload.vms.len() as i32).await {
-                Ok(_) => (StatusCode::OK, format!("Hypervisor '{}' registered successfully.", &payload.hostname)).into_response(),
+                Ok(_) => match Database::update_hypervisor_inventory(&db, &payload, labels).await {
+                    Ok(_) => (StatusCode::OK, format!("Hypervisor '{}' registered successfully.", &payload.hostname)).into_response(),
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor inventory: {}", e)).into_response(),
+                },
                 Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to register hypervisor: {}", e)).into_response()
             }
         },
@@ -672,7 +1450,7 @@ async fn list_ssh_pub_keys(Json(payload): Json<Tenant>) -> impl IntoResponse {
     }
 }
 
-async fn virtual_machine_scheduler(Json(payload): Json<VirtualMachineCreate>) -> impl IntoResponse {
+async fn virtual_machine_scheduler(headers: HeaderMap, Json(payload): Json<VirtualMachineCreate>) -> impl IntoResponse {
     let db = match Database::new().await {
         Ok(db) => db,
         Err(_) => {
@@ -693,13 +1471,66 @@ async fn virtual_machine_scheduler(Json(payload): Json<VirtualMachineCreate>) ->
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
     }
 
+    // Hypervisors keep volumes in a directory named like this one next to the VM ones.
+    if payload.name == "volumes" {
+        return (StatusCode::BAD_REQUEST, "VM name 'volumes' is reserved.").into_response();
+    }
+
     let valid_networking = vec!["l2-tenant", "l2-bridged"];
     if !valid_networking.contains(&payload.networking.as_str()) {
         return (StatusCode::BAD_REQUEST, format!("Invalid networking type, valid modes are: {}", valid_networking.join(", "))).into_response();
     }
 
+    // Flavors are the regular way to size VMs, raw values are reserved to admins.
+    let (flavor, cpu, ram, disk_size) = match (&payload.flavor, payload.cpu, payload.ram, payload.disk_size) {
+        (Some(name), None, None, None) => match Database::get_flavor_by_name(&db, name).await {
+            Ok(Some(flavor)) => {
+                let (cpu, ram, disk_size) = (flavor.cpu, flavor.ram, flavor.disk_size);
+                (Some(flavor), cpu, ram, disk_size)
+            },
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Flavor '{}' not found.", name)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        (Some(_), _, _, _) => return (StatusCode::BAD_REQUEST, "VM create request must include either a flavor or raw cpu, ram and disk_size values.").into_response(),
+        (None, Some(cpu), Some(ram), Some(disk_size)) if is_admin(&headers) => (None, cpu, ram, disk_size),
+        (None, Some(_), Some(_), Some(_)) => return (StatusCode::FORBIDDEN, "Raw cpu, ram and disk_size values are restricted to admins, please use a flavor.").into_response(),
+        (None, _, _, _) => return (StatusCode::BAD_REQUEST, "VM create request must include a flavor.").into_response(),
+    };
+
+    if cpu <= 0 || ram <= 0 || disk_size <= 0 {
+        return (StatusCode::BAD_REQUEST, "CPU, RAM and disk size must be positive.").into_response();
+    }
+
+    if let Some(flavor) = &flavor {
+        if !flavor.archs.contains(&payload.arch) {
+            return (StatusCode::BAD_REQUEST, format!("Flavor '{}' is not available for arch '{}'.", &flavor.name, &payload.arch)).into_response();
+        }
+    }
+
+    // Flavor bandwidth limits apply unless the request sets its own.
+    let qos = match &flavor {
+        Some(flavor) if payload.qos.is_empty() => flavor.qos(),
+        _ => payload.qos,
+    };
+
+    if let Err(e) = qos.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let image = match Database::get_image(&db, &payload.os, payload.image_version.as_deref(), &payload.arch, Some(&tenant_uuid)).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Image '{}' is not available for arch '{}'.", &payload.os, &payload.arch)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if disk_size < image.min_disk || ram < image.min_ram {
+        return (StatusCode::BAD_REQUEST, format!("Image '{}' requires at least {}G of disk and {}G of RAM.", &payload.os, image.min_disk, image.min_ram)).into_response();
+    }
+
+    let requested = ResourceUsage { vms: 1, vcpus: cpu.into(), ram: ram.into(), disk: disk_size.into(), ..Default::default() };
+
     let mut provider_network_name = Option::None;
-    let mut provider_network_vlan: Option<String> = Option::None;
+    let mut provider_network_static: Option<ProviderNetwork> = Option::None;
     match &payload.network {
         Some(network) => {
             if payload.networking != "l2-bridged" {
@@ -709,8 +1540,10 @@ async fn virtual_machine_scheduler(Json(payload): Json<VirtualMachineCreate>) ->
             let is_network_existing = Database::get_provider_network(&db, &network).await;
             match is_network_existing {
                 Ok(Some(provider)) => {
-                    provider_network_name = Some(provider.name);
-                    provider_network_vlan = Some(provider.vlan.to_string());
+                    provider_network_name = Some(provider.name.clone());
+                    if provider.dhcp == Some(false) {
+                        provider_network_static = Some(provider);
+                    }
                 }
                 Ok(None) => return (StatusCode::BAD_REQUEST, format!("Network '{}' does not exist.", &network)).into_response(),
                 Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
@@ -723,21 +1556,60 @@ async fn virtual_machine_scheduler(Json(payload): Json<VirtualMachineCreate>) ->
         }
     }
 
-    let mut target_hypervisor: String = String::new();
-    let mut target_hypervisor_uuid: Uuid = Uuid::nil();
-    let target_hypervisors = Database::get_hypervisors_min_hosted_vms(&db, &payload.arch).await.unwrap();
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
 
-    for hypervisor in target_hypervisors.iter() {
-        if hypervisor.total_ram - hypervisor.used_ram >= payload.ram && hypervisor.total_cpu - hypervisor.used_cpu >= payload.cpu {
-            target_hypervisor = hypervisor.hostname.clone();
-            target_hypervisor_uuid = hypervisor.id;
-            break;
-        }
+    let hypervisors = match Database::list_hypervisors(&db).await {
+        Ok(hypervisors) => hypervisors,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let server_group = match &payload.server_group {
+        Some(server_group) => match Database::get_server_group_by_name(&db, server_group, &tenant_uuid).await {
+            Ok(Some(server_group)) => Some(server_group),
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Server group '{}' not found.", server_group)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => None,
+    };
+
+    let group_hypervisors = match &server_group {
+        Some(server_group) => match Database::list_server_group_hypervisors(&db, &server_group.id).await {
+            Ok(hypervisors) => hypervisors,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => Vec::new(),
+    };
+
+    if let Err(e) = validate_labels(&payload.label_selector) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
     }
 
-    if target_hypervisor.is_empty() && target_hypervisor_uuid.is_nil() {
+    let image_file = image.file_name();
+    let mut request_spec = scheduler::RequestSpec {
+        cpu,
+        ram,
+        arch: &image.arch,
+        image: &image_file,
+        group_policy: server_group.as_ref().map(|server_group| server_group.policy.as_str()),
+        group_hypervisors: &group_hypervisors,
+        availability_zone: payload.availability_zone.as_deref(),
+        label_selector: &payload.label_selector,
+    };
+    let mut candidates = scheduler::schedule(&scheduler_config, hypervisors.clone(), &request_spec);
+    if candidates.is_empty() {
+        // Tell apart a hard group policy that cannot be met from a lack of resources.
+        if let Some(server_group) = server_group.as_ref().filter(|server_group| scheduler::is_hard_policy(&server_group.policy)) {
+            request_spec.group_policy = None;
+            if !scheduler::schedule(&scheduler_config, hypervisors, &request_spec).is_empty() {
+                return (StatusCode::CONFLICT, format!("No hypervisor satisfies the '{}' policy of server group '{}'.", &server_group.policy, &server_group.name)).into_response();
+            }
+        }
         return (StatusCode::BAD_REQUEST, "No hypervisor available with enough resources to schedule VM.").into_response();
     }
+    candidates.truncate(scheduler_config.max_attempts.max(1));
 
     let mac_addr = generate_mac_address().await;
     let mac_addr_as_string = format!(
@@ -748,103 +1620,294 @@ async fn virtual_machine_scheduler(Json(payload): Json<VirtualMachineCreate>) ->
 
     let mut create_vm_query = json!({
         "name": payload.name,
-        "memory": payload.ram * 1024,
-        "cpu": payload.cpu,
+        "memory": ram * 1024,
+        "cpu": cpu,
         "os": payload.os,
-        "disk": payload.disk_size,
+        "disk": disk_size,
         "ssh_pub_key": payload.ssh_pub_key,
         "tenant": payload.tenant,
         "mac_addr": mac_addr_as_string,
         "networking": payload.networking,
+        "fqdn": format!("{}.{}", &payload.name, dns_domain(&payload.vpc, &payload.tenant)),
+        "copy_on_write": payload.copy_on_write,
+        "image": {
+            "file": image.file_name(),
+            "format": image.format,
+            "default_user": image.default_user,
+            "cloud_init": image.cloud_init,
+            "checksum": image.checksum,
+        },
     });
 
-    if payload.networking == "l2-tenant" {
-        let ls_name = format!("{}-{}", &tenant_uuid, &payload.vpc);
-        let lsp_port_name = format!("{}-{}", &payload.tenant, &payload.name);
-        let response = add_lsp_to_ls(&lsp_port_name, &ls_name).await;
-        match response {
-            Ok(response) => {
-                let port_uuid = extract_uuid_from_response(&response).await;
-                match port_uuid {
-                    Ok(port_uuid) => {
-                        let add_mac_to_lsp = add_mac_to_lsp(&port_uuid, &mac_addr_as_string).await;
-                        match add_mac_to_lsp {
-                            Ok(_) => (),
-                            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add MAC to LSP: {}", e)).into_response(),
-                        }
+    if let Some(flavor) = &flavor {
+        create_vm_query["extra_specs"] = json!({
+            "hugepages": flavor.hugepages,
+            "cpu_pinning": flavor.cpu_pinning,
+        });
+    }
 
-                        let cidr = Database::get_vpc_cidr(&db, &payload.vpc, &tenant_uuid).await;
-                        match cidr {
-                            Ok(Some(cidr)) => {
-                                let dhcpv4_options = get_dhcpv4_options_id(&cidr).await;
-                                match dhcpv4_options {
-                                    Ok(dhcpv4_options) => {
-                                        let add_dhcpv4_options = add_dhcp_options_to_lsp(&port_uuid, &dhcpv4_options).await;
-                                        match add_dhcpv4_options {
-                                            Ok(_) => (),
-                                            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add DHCPv4 options to LSP: {}", e)).into_response(),
-                                        }                            
-                                    }
-                                    Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
-                                }                     
-                            }
-                            Ok(None) => return (StatusCode::BAD_REQUEST, "VPC CIDR not found").into_response(),
-                            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
-                        }
-                    }
-                    Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to extract UUID from OVN response: {}", e)).into_response(),
-                }
-            }
-            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create logical port: {}", e)).into_response(),
+    let vpc_uuid = match Database::get_vpc_by_name(&db, &payload.vpc, &tenant_uuid).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "VPC not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let pub_ssh_key_uuid = match Database::get_ssh_key(&db, &payload.ssh_pub_key).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "SSH public key not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let ls_name = match &provider_network_name {
+        Some(network) => provider_switch_name(network),
+        None => format!("{}-{}", &tenant_uuid, &payload.vpc),
+    };
+    let lsp_port_name = format!("{}-{}", &payload.tenant, &payload.name);
+    let fqdn = format!("{}.{}", &payload.name, dns_domain(&payload.vpc, &payload.tenant));
+
+    let cidr = if payload.networking == "l2-tenant" {
+        match Database::get_vpc_cidr(&db, &payload.vpc, &tenant_uuid).await {
+            Ok(Some(cidr)) => Some(cidr),
+            Ok(None) => return (StatusCode::BAD_REQUEST, "VPC CIDR not found").into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        }
+    } else {
+        None
+    };
+
+    // The VM row goes in before anything is set up in OVN or on a hypervisor,
+    // it counts against the tenant quota while in state 'creating'.
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &requested).await {
+        return e.into_response();
+    }
+
+    // Provider networks without an upstream DHCP server get a static address
+    // from the network allocation pool, delivered through cloud-init. It is
+    // picked and reserved with the VM record in the same transaction.
+    let mut static_ip: Option<IpNetwork> = None;
+    if let Some(provider) = &provider_network_static {
+        let used = match Database::lock_provider_network_static_ips(&mut tx, &provider.name).await {
+            Ok(used) => used,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+
+        let address = match ipam::allocate_address(provider, &used) {
+            Ok(address) => address,
+            Err(e) => return (StatusCode::CONFLICT, e).into_response(),
+        };
+
+        create_vm_query["network_config"] = json!({
+            "address": address.to_string(),
+            "gateway": provider.gateway.map(|gateway| gateway.ip().to_string()),
+            "dns_servers": provider.dns_servers.iter().map(|dns| dns.ip().to_string()).collect::<Vec<String>>(),
+        });
+        static_ip = Some(address);
+    }
+
+    if let Err(e) = Database::create_virtual_machine(
+        &mut *tx, &payload.name, &cpu, &ram,
+        &tenant_uuid, &vpc_uuid, &pub_ssh_key_uuid,
+        &disk_size, &candidates[0].id,
+        &payload.os, "creating", &payload.networking,
+        provider_network_name.clone(), static_ip, &image.id
+    ).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add VM to database: {}", e)).into_response();
+    }
+
+    if let Err(e) = Database::set_vm_mac_addr(&mut *tx, &payload.name, &tenant_uuid, &mac_addr_as_string).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add VM to database: {}", e)).into_response();
+    }
+
+    // Kept with the VM, evacuations and migrations honor them too.
+    if let Err(e) = Database::set_vm_placement(&mut *tx, &payload.name, &tenant_uuid, payload.availability_zone.as_deref(), &payload.label_selector).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add VM to database: {}", e)).into_response();
+    }
+
+    if !qos.is_empty() {
+        if let Err(e) = Database::update_vm_qos(&mut *tx, &payload.name, &tenant_uuid, &qos).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store VM QoS: {}", e)).into_response();
+        }
+    }
+
+    if let Some(flavor) = &flavor {
+        if let Err(e) = Database::set_vm_flavor(&mut *tx, &payload.name, &tenant_uuid, Some(&flavor.id)).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store VM flavor: {}", e)).into_response();
+        }
+    }
+
+    if payload.copy_on_write {
+        if let Err(e) = Database::set_vm_base_image(&mut *tx, &payload.name, &tenant_uuid, Some(&image.file_name())).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store VM base image: {}", e)).into_response();
+        }
+    }
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response();
+    }
+
+    let has_port = payload.networking == "l2-tenant" || provider_network_name.is_some();
+    let port = has_port.then_some((ls_name.as_str(), lsp_port_name.as_str(), fqdn.as_str()));
+    if has_port {
+        let response = match add_lsp_to_ls(&lsp_port_name, &ls_name).await {
+            Ok(response) => response,
+            Err(e) => {
+                discard_vm(&db, &payload.name, &tenant_uuid, None).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create logical port: {}", e)).into_response();
+            },
+        };
+
+        let port_uuid = match extract_uuid_from_response(&response).await {
+            Ok(port_uuid) => port_uuid,
+            Err(e) => {
+                discard_vm(&db, &payload.name, &tenant_uuid, port).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to extract UUID from OVN response: {}", e)).into_response();
+            },
+        };
+
+        let port_setup = match &cidr {
+            Some(cidr) => setup_tenant_port(&port_uuid, &mac_addr_as_string, cidr, &ls_name, &lsp_port_name, &fqdn, &qos).await,
+            None => {
+                let static_ip_address = static_ip.map(|ip| ip.ip().to_string());
+                add_static_address_to_lsp(&port_uuid, &mac_addr_as_string, static_ip_address.as_deref()).await
+                    .map_err(|e| format!("Failed to add addresses to LSP: {}", e))
+            },
+        };
+
+        if let Err(e) = port_setup {
+            discard_vm(&db, &payload.name, &tenant_uuid, port).await;
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+
+        // Bridged ports are shaped on the tap device through libvirt.
+        if cidr.is_none() && !qos.is_empty() {
+            create_vm_query["bandwidth"] = json!(qos);
         }
-    } else if payload.networking == "l2-bridged" {
-        create_vm_query["network"] = json!(provider_network_vlan);
     }
 
+    // Hypervisors are tried in the order the scheduler ranked them, whatever a
+    // failed one left behind is removed before moving on to the next one. The
+    // capacity claim follows the VM and goes away with its record.
     let client = Client::new();
-    let create_vm_response = client.post(format!("http://{}:3000/virtualmachine/create", target_hypervisor))
-        .header("Content-Type", "application/json")
-        .body(create_vm_query.to_string())
-        .send()
-        .await;
-
-    match create_vm_response {
-        Ok(response) => {
-            match response.status() {
-                StatusCode::OK => {
-                    let vpc_uuid = match Database::get_vpc_by_name(&db, &payload.vpc, &tenant_uuid).await {
-                        Ok(Some(uuid)) => uuid,
-                        Ok(None) => return (StatusCode::BAD_REQUEST, "VPC not found").into_response(),
-                        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
-                    };
-                
-                    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
-                        Ok(Some(uuid)) => uuid,
-                        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
-                        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
-                    };
-
-                    let pub_ssh_key_uuid = match Database::get_ssh_key(&db, &payload.ssh_pub_key).await {
-                        Ok(Some(uuid)) => uuid,
-                        Ok(None) => return (StatusCode::BAD_REQUEST, "SSH public key not found").into_response(),
-                        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
-                    };
-                
-                    match Database::create_virtual_machine(
-                        &db, &payload.name, &payload.cpu, &payload.ram,
-                        &tenant_uuid, &vpc_uuid, &pub_ssh_key_uuid,
-                        &payload.disk_size, &target_hypervisor_uuid,
-                        &payload.os, "created", &payload.networking,
-                        provider_network_name
-                    ).await {
-                        Ok(_) => (StatusCode::OK, format!("VM '{}' created successfully.", &payload.name)).into_response(),
-                        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add VM to database: {}", e)).into_response(),
-                    }
-                }
-                _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VM on hypervisor '{}'.", &target_hypervisor)).into_response(),
+    let mut attempts: Vec<String> = Vec::new();
+    let mut created = false;
+    for candidate in candidates.iter() {
+        let placed = match db.begin().await {
+            Ok(mut tx) => match place_vm(&mut tx, &payload.name, &tenant_uuid, server_group.as_ref(), candidate, &scheduler_config, &request_spec).await {
+                Ok(_) => tx.commit().await.map_err(|e| e.to_string()),
+                Err((_, e)) => Err(e),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = placed {
+            attempts.push(format!("{}: {}", &candidate.hostname, e));
+            continue;
+        }
+
+        let create_vm_response = client.post(format!("http://{}:3000/virtualmachine/create", &candidate.hostname))
+            .header("Content-Type", "application/json")
+            .body(create_vm_query.to_string())
+            .send()
+            .await;
+
+        let create_vm_error = match create_vm_response {
+            Ok(response) if response.status() == StatusCode::OK => None,
+            Ok(response) => Some(format!("Failed to create VM: {}", response.text().await.unwrap_or_default())),
+            Err(e) => Some(format!("Failed to connect to hypervisor compute API: {}", e)),
+        };
+
+        let attempt = format!("{}: {}", &candidate.hostname, create_vm_error.as_deref().unwrap_or("created"));
+        if let Err(e) = Database::add_vm_scheduling_attempt(&db, &payload.name, &tenant_uuid, &attempt).await {
+            eprintln!("Failed to record scheduling attempt of VM '{}': {}", &payload.name, e);
+        }
+        attempts.push(attempt);
+
+        if create_vm_error.is_none() {
+            created = true;
+            break;
+        }
+
+        let delete_vm_query = json!({
+            "name": payload.name,
+            "tenant": payload.tenant,
+        });
+
+        if let Err(e) = post_to_hypervisor(&candidate.hostname, "/virtualmachine/delete", &delete_vm_query).await {
+            eprintln!("Failed to clean up VM '{}' on hypervisor '{}': {}", &payload.name, &candidate.hostname, e);
+        }
+    }
+
+    if !created {
+        discard_vm(&db, &payload.name, &tenant_uuid, port).await;
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VM '{}' after {} attempt(s): {}", &payload.name, attempts.len(), attempts.join("; "))).into_response();
+    }
+
+    // The VM exists on the hypervisor at this point, the agent stats correct
+    // its state if this update is lost.
+    if let Err(e) = Database::update_vm_state(&db, &payload.name, &tenant_uuid, "created").await {
+        eprintln!("Failed to update VM '{}' state: {}", &payload.name, e);
+    }
+
+    (StatusCode::OK, format!("VM '{}' created successfully.", &payload.name)).into_response()
+}
+
+async fn setup_tenant_port(
+    port_uuid: &str,
+    mac_addr: &str,
+    cidr: &str,
+    ls_name: &str,
+    lsp_port_name: &str,
+    fqdn: &str,
+    qos: &VirtualMachineQos
+) -> Result<(), String> {
+    add_mac_to_lsp(port_uuid, mac_addr).await.map_err(|e| format!("Failed to add MAC to LSP: {}", e))?;
+
+    let dhcpv4_options = get_dhcpv4_options_id(cidr).await.map_err(|e| format!("Database error: {}", e))?;
+    add_dhcp_options_to_lsp(port_uuid, &dhcpv4_options).await.map_err(|e| format!("Failed to add DHCPv4 options to LSP: {}", e))?;
+
+    // Northd may not have allocated the dynamic address yet, in that case the
+    // record is added once the agent reports the VM IPs.
+    match get_lsp_dynamic_addresses(lsp_port_name).await {
+        Ok(ips) if !ips.is_empty() => {
+            if let Err(e) = set_dns_record(ls_name, fqdn, &ips).await {
+                eprintln!("Failed to add DNS record for '{}': {}", fqdn, e);
             }
         }
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to connect to hypervisor compute API '{}': {}", &target_hypervisor, e)).into_response(),
+        Ok(_) => (),
+        Err(e) => eprintln!("Failed to fetch dynamic addresses for '{}': {}", lsp_port_name, e),
+    }
+
+    if !qos.is_empty() {
+        set_lsp_qos(ls_name, lsp_port_name, qos.ingress_kbps, qos.egress_kbps, qos.burst_kbit).await
+            .map_err(|e| format!("Failed to apply QoS rules: {}", e))?;
+    }
+
+    Ok(())
+}
+
+// Undoes a VM create that failed after its record was committed. The logical
+// switch port goes away with it, port names are unique in OVN and the VM name
+// could not be used again otherwise.
+async fn discard_vm(db: &sqlx::Pool<sqlx::Postgres>, name: &str, tenant: &Uuid, port: Option<(&str, &str, &str)>) {
+    if let Some((ls_name, port_name, fqdn)) = port {
+        if let Err(e) = set_lsp_qos(ls_name, port_name, None, None, None).await {
+            eprintln!("Failed to remove QoS rules of VM '{}': {}", name, e);
+        }
+        if let Err(e) = remove_dns_record(ls_name, fqdn).await {
+            eprintln!("Failed to remove DNS record for VM '{}': {}", name, e);
+        }
+        if let Err(e) = remove_lsp(port_name, ls_name).await {
+            eprintln!("Failed to remove logical port of VM '{}': {}", name, e);
+        }
+    }
+
+    if let Err(e) = Database::delete_virtual_machine(db, name, tenant).await {
+        eprintln!("Failed to remove VM '{}' from database: {}", name, e);
     }
 }
 
@@ -864,12 +1927,29 @@ async fn delete_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl In
 
     match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
         Ok(Some(vm)) => {
+            // External snapshots leave attached volumes pointing at overlays,
+            // deleting them first commits the data back into the volumes.
+            match Database::count_external_snapshots(&db, &vm.id, None).await {
+                Ok(0) => (),
+                Ok(_) => return (StatusCode::BAD_REQUEST, format!("VM '{}' has external snapshots, please delete them first.", &vm.name)).into_response(),
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            }
+
             let tenant_name = Database::get_tenant_by_id(&db, &vm.tenant).await;
             match tenant_name {
                 Ok(Some(tenant_name)) => {
                     let port_name = format!("{}-{}", &tenant_name, &vm.name);
                     if let Ok(Some(vpc)) = Database::get_vpc_by_id(&db, &vm.vpc, &tenant_uuid).await {
-                        let ls_name = format!("{}-{}", &tenant_uuid, &vpc);
+                        let ls_name = match &vm.network {
+                            Some(network) if vm.networking == "l2-bridged" => provider_switch_name(network),
+                            _ => format!("{}-{}", &tenant_uuid, &vpc),
+                        };
+
+                        let fqdn = format!("{}.{}", &vm.name, dns_domain(&vpc, &tenant_name));
+                        if let Err(e) = remove_dns_record(&ls_name, &fqdn).await {
+                            eprintln!("Failed to remove DNS record for VM '{}': {}", &vm.name, e);
+                        }
+
                         let delete_lsp = remove_lsp(&port_name, &ls_name).await;
                         match delete_lsp {
                             Ok(_) => (),
@@ -905,7 +1985,12 @@ async fn delete_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl In
                 Ok(response) => {
                     match response.status() {
                         StatusCode::OK => {
-                            match Database::delete_virtual_machine(&db, &payload.name).await {
+                            // Volumes outlive the VM, they go back to the available pool.
+                            if let Err(e) = Database::detach_vm_volumes(&db, &vm.id).await {
+                                return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to detach VM volumes: {}", e)).into_response();
+                            }
+
+                            match Database::delete_virtual_machine(&db, &payload.name, &tenant_uuid).await {
                                 Ok(_) => (StatusCode::OK, format!("VM '{}' deleted successfully.", &payload.name)).into_response(),
                                 Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete VM from database: {}", e)).into_response(),
                             }
@@ -921,6 +2006,68 @@ async fn delete_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl In
     }
 }
 
+async fn qos_vm_handler(Json(payload): Json<VirtualMachineQosUpdate>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if let Err(e) = payload.qos.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    // Limits are applied live: OVN reprograms the meters for tenant ports and
+    // libvirt updates the tap shaping of bridged ports without a reboot.
+    if vm.networking == "l2-tenant" {
+        let vpc = match Database::get_vpc_by_id(&db, &vm.vpc, &tenant_uuid).await {
+            Ok(Some(vpc)) => vpc,
+            Ok(None) => return (StatusCode::BAD_REQUEST, "VPC not found").into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+
+        let ls_name = format!("{}-{}", &tenant_uuid, &vpc);
+        let lsp_port_name = format!("{}-{}", &payload.tenant, &vm.name);
+        if let Err(e) = set_lsp_qos(&ls_name, &lsp_port_name, payload.qos.ingress_kbps, payload.qos.egress_kbps, payload.qos.burst_kbit).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to apply QoS rules: {}", e)).into_response();
+        }
+    } else {
+        let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+            Ok(Some(hypervisor_hostname)) => hypervisor_hostname,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+
+        let bandwidth_query = json!({
+            "name": vm.name,
+            "tenant": payload.tenant,
+            "bandwidth": payload.qos,
+        });
+
+        if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/virtualmachine/bandwidth", &bandwidth_query).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update bandwidth: {}", e)).into_response();
+        }
+    }
+
+    match Database::update_vm_qos(&db, &payload.name, &tenant_uuid, &payload.qos).await {
+        Ok(_) => (StatusCode::OK, format!("QoS for VM '{}' updated successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to store VM QoS: {}", e)).into_response(),
+    }
+}
+
 async fn list_vm_handler(Json(payload): Json<Tenant>) -> impl IntoResponse {
     let db = match Database::new().await {
         Ok(db) => db,
@@ -979,15 +2126,51 @@ async fn create_provider_network_handler(Json(payload): Json<ProviderNetwork>) -
         ).into_response();
     }
 
-    match Database::create_provider_network(&db, &payload.name, &payload.vlan, &payload.subnet).await {
+    let subnet = match ipam::parse_subnet(&payload.subnet) {
+        Ok(subnet) => subnet,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    if let Err(e) = ipam::allocation_range(&subnet, payload.allocation_start, payload.allocation_end) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    match payload.gateway {
+        Some(gateway) if !ipam::subnet_contains(&subnet, &gateway) => {
+            return (StatusCode::BAD_REQUEST, format!("Gateway {} is outside of subnet {}.", gateway.ip(), subnet)).into_response();
+        }
+        None if payload.dhcp == Some(false) => {
+            return (StatusCode::BAD_REQUEST, "Provider networks without DHCP must include a gateway.").into_response();
+        }
+        _ => (),
+    }
+
+    // The record goes in first so that a duplicate name is refused before OVN
+    // is touched, it is only committed once the switch exists.
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = Database::create_provider_network(&mut *tx, &payload).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create provider network: {}", e)).into_response();
+    }
+
+    if let Err(e) = create_provider_switch(&payload.name, &payload.vlan, &payload.subnet).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create provider logical switch: {}", e)).into_response();
+    }
+
+    match tx.commit().await {
         Ok(_) => (
             StatusCode::OK,
             format!("Provider network '{}' created successfully.", payload.name),
         ).into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to create provider network: {}", e),
-        ).into_response(),
+        Err(e) => {
+            if let Err(e) = delete_provider_switch(&payload.name).await {
+                eprintln!("Failed to remove provider logical switch '{}': {}", &payload.name, e);
+            }
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create provider network: {}", e)).into_response()
+        }
     }
 }
 
@@ -1006,6 +2189,10 @@ async fn delete_provider_network_handler(Json(payload): Json<ProviderNetworkDele
         ).into_response();
     }
 
+    if let Err(e) = delete_provider_switch(&payload.name).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete provider logical switch: {}", e)).into_response();
+    }
+
     match Database::delete_provider_network(&db, &payload.name).await {
         Ok(_) => (
             StatusCode::OK,
@@ -1018,7 +2205,7 @@ async fn delete_provider_network_handler(Json(payload): Json<ProviderNetworkDele
     }
 }
 
-async fn list_hypervisors_handler() -> impl IntoResponse {
+async fn list_hypervisors_handler(Query(query): Query<HypervisorListQuery>) -> impl IntoResponse {
     let db = match Database::new().await {
         Ok(db) => db,
         Err(_) => {
@@ -1033,6 +2220,27 @@ async fn list_hypervisors_handler() -> impl IntoResponse {
         }
     };
 
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
+
+    let label_selector: Vec<String> = query.labels.iter()
+        .flat_map(|labels| labels.split(','))
+        .filter(|label| !label.is_empty())
+        .map(|label| label.to_string())
+        .collect();
+
+    let hypervisors: Vec<serde_json::Value> = hypervisors.iter()
+        .filter(|hypervisor| scheduler::in_zone(hypervisor, query.availability_zone.as_deref()))
+        .filter(|hypervisor| scheduler::has_labels(hypervisor, &label_selector))
+        .map(|hypervisor| {
+            let mut hypervisor_json = json!(hypervisor);
+            hypervisor_json["capacity"] = json!(scheduler::capacity(&scheduler_config, hypervisor));
+            hypervisor_json
+        })
+        .collect();
+
     let hypervisors_json = match serde_json::to_string(&hypervisors) {
         Ok(json) => json,
         Err(_) => {
@@ -1043,6 +2251,2085 @@ async fn list_hypervisors_handler() -> impl IntoResponse {
     (StatusCode::OK, hypervisors_json).into_response()
 }
 
+async fn hypervisor_labels_handler(headers: HeaderMap, Json(payload): Json<HypervisorLabels>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Hypervisor labels can only be managed by admins.").into_response();
+    }
+
+    if let Err(e) = validate_labels(payload.labels.as_deref().unwrap_or_default()) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match Database::set_hypervisor_labels(&db, &payload).await {
+        Ok(0) => (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+        Ok(_) => (StatusCode::OK, format!("Labels of hypervisor '{}' updated successfully.", &payload.hostname)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response(),
+    }
+}
+
+async fn hypervisor_overcommit_handler(headers: HeaderMap, Json(payload): Json<HypervisorOvercommit>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Hypervisor overcommit can only be managed by admins.").into_response();
+    }
+
+    if payload.cpu_allocation_ratio.is_some_and(|ratio| ratio <= 0.0) || payload.ram_allocation_ratio.is_some_and(|ratio| ratio <= 0.0) {
+        return (StatusCode::BAD_REQUEST, "Allocation ratios must be greater than 0.").into_response();
+    }
+
+    if payload.reserved_cpu.is_some_and(|cpu| cpu < 0) || payload.reserved_ram.is_some_and(|ram| ram < 0) {
+        return (StatusCode::BAD_REQUEST, "Reserved resources cannot be negative.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match Database::set_hypervisor_overcommit(&db, &payload).await {
+        Ok(0) => (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+        Ok(_) => (StatusCode::OK, format!("Overcommit of hypervisor '{}' updated successfully.", &payload.hostname)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response(),
+    }
+}
+
+async fn set_hypervisor_maintenance(headers: &HeaderMap, hostname: &str, maintenance: bool) -> axum::response::Response {
+    if !is_admin(headers) {
+        return (StatusCode::FORBIDDEN, "Hypervisor maintenance can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match Database::set_hypervisor_maintenance(&db, hostname, maintenance).await {
+        Ok(0) => (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", hostname)).into_response(),
+        Ok(_) => (StatusCode::OK, format!("Hypervisor '{}' {} successfully.", hostname, if maintenance { "disabled" } else { "enabled" })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response(),
+    }
+}
+
+async fn disable_hypervisor_handler(headers: HeaderMap, Json(payload): Json<HypervisorMaintenance>) -> impl IntoResponse {
+    set_hypervisor_maintenance(&headers, &payload.hostname, true).await
+}
+
+async fn enable_hypervisor_handler(headers: HeaderMap, Json(payload): Json<HypervisorMaintenance>) -> impl IntoResponse {
+    set_hypervisor_maintenance(&headers, &payload.hostname, false).await
+}
+
+fn outcome_report(hostname: &str, outcomes: Vec<VmOutcome>) -> axum::response::Response {
+    let status = if outcomes.iter().any(|outcome| outcome.outcome == "failed") {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::OK
+    };
+
+    (status, json!({ "hypervisor": hostname, "vms": outcomes }).to_string()).into_response()
+}
+
+// Disables the hypervisor and migrates its VMs elsewhere, running ones live.
+// Running VMs that cannot be migrated are stopped instead, they keep
+// autostart and come back once the hypervisor reboots.
+async fn drain_hypervisor_handler(headers: HeaderMap, Json(payload): Json<HypervisorMaintenance>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Hypervisor maintenance can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let hypervisor_uuid = match Database::get_hypervisor_by_hostname(&db, &payload.hostname).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = Database::set_hypervisor_maintenance(&db, &payload.hostname, true).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response();
+    }
+
+    let source = match Database::get_hypervisor_resources(&db, &hypervisor_uuid).await {
+        Ok(Some(hypervisor)) => hypervisor,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
+
+    let vms = match Database::list_hypervisor_vms(&db, &hypervisor_uuid).await {
+        Ok(vms) => vms,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut outcomes = Vec::new();
+    for vm in vms.iter() {
+        let tenant = match Database::get_tenant_by_id(&db, &vm.tenant).await {
+            Ok(Some(tenant)) => tenant,
+            Ok(None) => continue,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+
+        let mut outcome = VmOutcome { name: vm.name.clone(), tenant: tenant.clone(), outcome: "skipped".to_string(), hypervisor: Some(payload.hostname.clone()), error: None };
+        if vm.state == "running" || vm.state == "shutoff" {
+            match migrate_vm(&db, &scheduler_config, &source, vm, &tenant, &MigrationOptions::default(), None).await {
+                Ok(hostname) => {
+                    outcome.outcome = "migrated".to_string();
+                    outcome.hypervisor = Some(hostname);
+                },
+                Err((StatusCode::CONFLICT, e)) if vm.state == "running" => {
+                    let stop_vm_query = json!({
+                        "name": vm.name,
+                        "tenant": tenant,
+                    });
+
+                    match post_to_hypervisor(&payload.hostname, "/virtualmachine/stop", &stop_vm_query).await {
+                        Ok(_) => {
+                            outcome.outcome = "stopped".to_string();
+                            outcome.error = Some(e);
+                        },
+                        Err(e) => {
+                            outcome.outcome = "failed".to_string();
+                            outcome.error = Some(e);
+                        },
+                    }
+                },
+                // Shut off VMs that cannot be migrated stay behind.
+                Err((StatusCode::CONFLICT, e)) => outcome.error = Some(e),
+                Err((_, e)) => {
+                    outcome.outcome = "failed".to_string();
+                    outcome.error = Some(e);
+                },
+            }
+        }
+        outcomes.push(outcome);
+    }
+
+    outcome_report(&payload.hostname, outcomes)
+}
+
+// Recreates the VMs of a down hypervisor elsewhere, from their disks when the
+// images directory is on shared storage or from their images otherwise. Their
+// domains on the down hypervisor are fenced so that it does not boot the same
+// disks again if it comes back.
+async fn evacuate_hypervisor_handler(headers: HeaderMap, Json(payload): Json<HypervisorEvacuate>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Hypervisor maintenance can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let source = match Database::get_hypervisor_by_hostname(&db, &payload.hostname).await {
+        Ok(Some(uuid)) => match Database::get_hypervisor_resources(&db, &uuid).await {
+            Ok(Some(hypervisor)) => hypervisor,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &payload.hostname)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if source.status != "down" {
+        return (StatusCode::CONFLICT, format!("Hypervisor '{}' is {}, only down hypervisors can be evacuated, drain it instead.", &payload.hostname, &source.status)).into_response();
+    }
+
+    if let Err(e) = Database::set_hypervisor_maintenance(&db, &payload.hostname, true).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response();
+    }
+
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
+
+    let vms = match Database::list_hypervisor_vms(&db, &source.id).await {
+        Ok(vms) => vms,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut outcomes = Vec::new();
+    for vm in vms.iter() {
+        let tenant = match Database::get_tenant_by_id(&db, &vm.tenant).await {
+            Ok(Some(tenant)) => tenant,
+            Ok(None) => continue,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+
+        // Volumes and snapshot metadata live on the down hypervisor only, such
+        // VMs stay behind until it comes back.
+        let volumes = match Database::list_vm_volumes(&db, &vm.id).await {
+            Ok(volumes) => volumes,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+        let snapshots = match Database::list_snapshots(&db, &vm.tenant, Some(vm.id)).await {
+            Ok(snapshots) => snapshots,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        };
+        let skipped = if !volumes.is_empty() {
+            Some("VM has volumes attached, they cannot be recreated elsewhere.")
+        } else if !snapshots.is_empty() {
+            Some("VM has snapshots, they cannot be recreated elsewhere.")
+        } else {
+            None
+        };
+        if let Some(reason) = skipped {
+            outcomes.push(VmOutcome { name: vm.name.clone(), tenant, outcome: "skipped".to_string(), hypervisor: Some(payload.hostname.clone()), error: Some(reason.to_string()) });
+            continue;
+        }
+
+        let outcome = match evacuate_vm(&db, &scheduler_config, &source, vm, &tenant, payload.from_image).await {
+            Ok(hostname) => VmOutcome { name: vm.name.clone(), tenant, outcome: "evacuated".to_string(), hypervisor: Some(hostname), error: None },
+            Err(e) => VmOutcome { name: vm.name.clone(), tenant, outcome: "failed".to_string(), hypervisor: Some(payload.hostname.clone()), error: Some(e) },
+        };
+        outcomes.push(outcome);
+    }
+
+    outcome_report(&payload.hostname, outcomes)
+}
+
+async fn evacuate_vm(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    scheduler_config: &scheduler::SchedulerConfig,
+    source: &HypervisorScheduler,
+    vm: &VirtualMachine,
+    tenant: &str,
+    from_image: bool
+) -> Result<String, String> {
+    // VMs created before their MAC was recorded still have it on their port.
+    let mac_addr = match &vm.mac_addr {
+        Some(mac_addr) => mac_addr.clone(),
+        None => get_lsp_mac_address(&format!("{}-{}", tenant, &vm.name)).await
+            .map_err(|e| format!("Failed to read MAC address from OVN: {}", e))?
+            .ok_or("MAC address of the VM is unknown.")?,
+    };
+    let image = match vm.image {
+        Some(image) => Database::get_image_by_id(db, &image).await
+            .map_err(|e| format!("Database error: {}", e))?
+            .ok_or("Image of the VM no longer exists.")?,
+        None => return Err("VM has no image to be recreated from.".to_string()),
+    };
+    let vpc = Database::get_vpc_by_id(db, &vm.vpc, &vm.tenant).await
+        .map_err(|e| format!("Database error: {}", e))?
+        .unwrap_or_default();
+    let ssh_pub_key = Database::get_ssh_key_name(db, &vm.ssh_pub_key).await
+        .map_err(|e| format!("Database error: {}", e))?
+        .unwrap_or_default();
+
+    let mut create_vm_query = json!({
+        "name": vm.name,
+        "memory": vm.ram * 1024,
+        "cpu": vm.cpu,
+        "os": vm.os,
+        "disk": vm.disk_size,
+        "ssh_pub_key": ssh_pub_key,
+        "tenant": tenant,
+        "mac_addr": mac_addr,
+        "networking": vm.networking,
+        "fqdn": format!("{}.{}", &vm.name, dns_domain(&vpc, tenant)),
+        "copy_on_write": vm.base_image.is_some(),
+        "reuse_disk": !from_image,
+        "image": {
+            "file": image.file_name(),
+            "format": image.format,
+            "default_user": image.default_user,
+            "cloud_init": image.cloud_init,
+            "checksum": image.checksum,
+        },
+    });
+
+    if let Some(flavor) = vm.flavor {
+        if let Ok(Some(flavor)) = Database::get_flavor_by_id(db, &flavor).await {
+            create_vm_query["extra_specs"] = json!({
+                "hugepages": flavor.hugepages,
+                "cpu_pinning": flavor.cpu_pinning,
+            });
+        }
+    }
+
+    if let (Some(network), Some(address)) = (&vm.network, vm.static_ip) {
+        if let Ok(Some(provider)) = Database::get_provider_network(db, network).await {
+            create_vm_query["network_config"] = json!({
+                "address": address.to_string(),
+                "gateway": provider.gateway.map(|gateway| gateway.ip().to_string()),
+                "dns_servers": provider.dns_servers.iter().map(|dns| dns.ip().to_string()).collect::<Vec<String>>(),
+            });
+        }
+    }
+
+    let server_group = match vm.server_group {
+        Some(server_group) => Database::get_server_group_by_id(db, &server_group).await.map_err(|e| format!("Database error: {}", e))?,
+        None => None,
+    };
+
+    // Members on the evacuated hypervisor follow wherever the first one lands.
+    let group_hypervisors: Vec<Uuid> = match &server_group {
+        Some(server_group) => Database::list_server_group_hypervisors(db, &server_group.id).await
+            .map_err(|e| format!("Database error: {}", e))?
+            .into_iter()
+            .filter(|hypervisor| *hypervisor != source.id)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let hypervisors: Vec<HypervisorScheduler> = Database::list_hypervisors(db).await
+        .map_err(|e| format!("Database error: {}", e))?
+        .into_iter()
+        .filter(|hypervisor| hypervisor.id != source.id)
+        .collect();
+
+    let image_file = image.file_name();
+    let request_spec = scheduler::RequestSpec {
+        cpu: vm.cpu,
+        ram: vm.ram,
+        arch: &image.arch,
+        image: &image_file,
+        group_policy: server_group.as_ref().map(|server_group| server_group.policy.as_str()),
+        group_hypervisors: &group_hypervisors,
+        availability_zone: vm.availability_zone.as_deref(),
+        label_selector: &vm.label_selector,
+    };
+
+    let mut candidates = scheduler::schedule(scheduler_config, hypervisors, &request_spec);
+    candidates.truncate(scheduler_config.max_attempts.max(1));
+    if candidates.is_empty() {
+        return Err("No hypervisor available with enough resources to host the VM.".to_string());
+    }
+
+    fence_vm(&source.hostname, &vm.name, tenant).await?;
+
+    let mut attempts: Vec<String> = Vec::new();
+    for candidate in candidates.iter() {
+        let placed = match db.begin().await {
+            Ok(mut tx) => match place_vm(&mut tx, &vm.name, &vm.tenant, server_group.as_ref(), candidate, scheduler_config, &request_spec).await {
+                Ok(_) => tx.commit().await.map_err(|e| e.to_string()),
+                Err((_, e)) => Err(e),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = placed {
+            attempts.push(format!("{}: {}", &candidate.hostname, e));
+            continue;
+        }
+
+        let create_vm_error = post_to_hypervisor(&candidate.hostname, "/virtualmachine/create", &create_vm_query).await.err();
+        let attempt = format!("{}: {}", &candidate.hostname, create_vm_error.as_deref().unwrap_or("evacuated"));
+        if let Err(e) = Database::add_vm_scheduling_attempt(db, &vm.name, &vm.tenant, &attempt).await {
+            eprintln!("Failed to record scheduling attempt of VM '{}': {}", &vm.name, e);
+        }
+        attempts.push(attempt);
+
+        if create_vm_error.is_none() {
+            if let Err(e) = Database::update_vm_state(db, &vm.name, &vm.tenant, "created").await {
+                eprintln!("Failed to update VM '{}' state: {}", &vm.name, e);
+            }
+            return Ok(candidate.hostname.clone());
+        }
+
+        // Deleting a VM recreated on its shared disk would remove that disk.
+        if from_image {
+            let delete_vm_query = json!({
+                "name": vm.name,
+                "tenant": tenant,
+            });
+
+            if let Err(e) = post_to_hypervisor(&candidate.hostname, "/virtualmachine/delete", &delete_vm_query).await {
+                eprintln!("Failed to clean up VM '{}' on hypervisor '{}': {}", &vm.name, &candidate.hostname, e);
+            }
+        }
+    }
+
+    // The VM stays with the down hypervisor so that it can be evacuated again.
+    if let Err(e) = Database::update_vm_hypervisor(db, &vm.name, &vm.tenant, &source.id).await {
+        eprintln!("Failed to update VM '{}' hypervisor: {}", &vm.name, e);
+    }
+    if let Err(e) = Database::release_capacity_claim(db, &vm.name, &vm.tenant).await {
+        eprintln!("Failed to release capacity claim of VM '{}': {}", &vm.name, e);
+    }
+
+    Err(attempts.join("; "))
+}
+
+// A hypervisor that still answers drops the domain, and with it autostart,
+// before the VM is recreated elsewhere. An unreachable one does the same once
+// it reports back, see hypervisor_stats_handler.
+async fn fence_vm(hostname: &str, name: &str, tenant: &str) -> Result<(), String> {
+    let client = Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(5))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let undefine_vm_query = json!({
+        "name": name,
+        "tenant": tenant,
+    });
+
+    let response = match client.post(format!("http://{}:3000/virtualmachine/undefine", hostname))
+        .header("Content-Type", "application/json")
+        .body(undefine_vm_query.to_string())
+        .send()
+        .await {
+        Ok(response) => response,
+        Err(_) => return Ok(()),
+    };
+
+    let status = response.status();
+    if status != StatusCode::OK {
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Failed to fence VM on hypervisor '{}', it returned {}: {}", hostname, status, body));
+    }
+
+    Ok(())
+}
+
+async fn migrate_vm_handler(headers: HeaderMap, Json(payload): Json<VirtualMachineMigrate>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "VMs can only be migrated by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let source = match Database::get_hypervisor_resources(&db, &vm.hypervisor).await {
+        Ok(Some(hypervisor)) => hypervisor,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
+
+    match migrate_vm(&db, &scheduler_config, &source, &vm, &payload.tenant, &payload.options, None).await {
+        Ok(hostname) => (StatusCode::OK, format!("VM '{}' migrated from '{}' to '{}'.", &payload.name, &source.hostname, hostname)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn migration_progress_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let source = match vm.migration_source {
+        Some(source) if vm.state == "migrating" => source,
+        _ => return (StatusCode::BAD_REQUEST, format!("VM '{}' is not being migrated.", &payload.name)).into_response(),
+    };
+
+    let source = match Database::get_hypervisor_resources(&db, &source).await {
+        Ok(Some(hypervisor)) => hypervisor,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &source)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let progress_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+    });
+
+    match post_to_hypervisor(&source.hostname, "/virtualmachine/migrate/progress", &progress_query).await {
+        Ok(progress) => (StatusCode::OK, progress).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to get migration progress: {}", e)).into_response(),
+    }
+}
+
+// Shut off VMs are cold migrated, the target hypervisor pulls their directory
+// from the source one and defines them. The source copy is only removed once
+// the VM record points at the target. Running VMs are live migrated by the
+// source hypervisor. Returns the target hostname.
+async fn migrate_vm(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    scheduler_config: &scheduler::SchedulerConfig,
+    source: &HypervisorScheduler,
+    vm: &VirtualMachine,
+    tenant: &str,
+    options: &MigrationOptions,
+    size: Option<(i32, i32)>
+) -> Result<String, (StatusCode, String)> {
+    let db_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e));
+
+    let live = match vm.state.as_str() {
+        "shutoff" => false,
+        "running" => true,
+        state => return Err((StatusCode::CONFLICT, format!("VM '{}' is {}, only running or shut off VMs can be migrated.", &vm.name, state))),
+    };
+
+    // Tenant traffic follows the VM by rebinding its logical switch port.
+    if live && vm.networking != "l2-tenant" {
+        return Err((StatusCode::CONFLICT, format!("VM '{}' uses {} networking, only l2-tenant VMs can be live migrated.", &vm.name, &vm.networking)));
+    }
+
+    // Volumes and snapshot metadata live on the source hypervisor only.
+    if !Database::list_vm_volumes(db, &vm.id).await.map_err(db_error)?.is_empty() {
+        return Err((StatusCode::CONFLICT, format!("VM '{}' has volumes attached, detach them before migrating it.", &vm.name)));
+    }
+    if !Database::list_snapshots(db, &vm.tenant, Some(vm.id)).await.map_err(db_error)?.is_empty() {
+        return Err((StatusCode::CONFLICT, format!("VM '{}' has snapshots, delete them before migrating it.", &vm.name)));
+    }
+
+    let image = match vm.image {
+        Some(image) => Database::get_image_by_id(db, &image).await.map_err(db_error)?,
+        None => None,
+    };
+
+    let server_group = match vm.server_group {
+        Some(server_group) => Database::get_server_group_by_id(db, &server_group).await.map_err(db_error)?,
+        None => None,
+    };
+
+    // The VM itself no longer counts as a member on the source hypervisor.
+    let mut group_hypervisors: Vec<Uuid> = match &server_group {
+        Some(server_group) => Database::list_server_group_hypervisors(db, &server_group.id).await.map_err(db_error)?,
+        None => Vec::new(),
+    };
+    if let Some(position) = group_hypervisors.iter().position(|hypervisor| *hypervisor == source.id) {
+        group_hypervisors.remove(position);
+    }
+
+    let mut hypervisors: Vec<HypervisorScheduler> = Database::list_hypervisors(db).await
+        .map_err(db_error)?
+        .into_iter()
+        .filter(|hypervisor| hypervisor.id != source.id)
+        .collect();
+
+    if let Some(target) = &options.hypervisor {
+        if *target == source.hostname {
+            return Err((StatusCode::BAD_REQUEST, format!("VM '{}' is already on hypervisor '{}'.", &vm.name, target)));
+        }
+
+        hypervisors.retain(|hypervisor| hypervisor.hostname == *target);
+        if hypervisors.is_empty() {
+            return Err((StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", target)));
+        }
+    }
+
+    // Domains use host-passthrough, a running guest cannot change CPU model.
+    if live {
+        hypervisors.retain(|hypervisor| hypervisor.cpu_model.is_some() && hypervisor.cpu_model == source.cpu_model);
+        if let (Some(target), true) = (&options.hypervisor, hypervisors.is_empty()) {
+            return Err((StatusCode::CONFLICT, format!("Hypervisor '{}' has a different CPU model than '{}'.", target, &source.hostname)));
+        }
+    }
+
+    // Resizes move the VM to a hypervisor with room for its new size.
+    let (cpu, ram) = size.unwrap_or((vm.cpu, vm.ram));
+    let image_file = image.as_ref().map(|image| image.file_name()).unwrap_or_default();
+    let request_spec = scheduler::RequestSpec {
+        cpu,
+        ram,
+        arch: &source.arch,
+        image: &image_file,
+        group_policy: server_group.as_ref().map(|server_group| server_group.policy.as_str()),
+        group_hypervisors: &group_hypervisors,
+        availability_zone: vm.availability_zone.as_deref(),
+        label_selector: &vm.label_selector,
+    };
+
+    let mut candidates = scheduler::schedule(scheduler_config, hypervisors, &request_spec);
+    candidates.truncate(scheduler_config.max_attempts.max(1));
+    if candidates.is_empty() {
+        return Err((StatusCode::CONFLICT, format!("No hypervisor available to host VM '{}'.", &vm.name)));
+    }
+
+    let image_spec = image.as_ref().map(|image| json!({
+        "file": image.file_name(),
+        "format": image.format,
+        "default_user": image.default_user,
+        "cloud_init": image.cloud_init,
+        "checksum": image.checksum,
+    }));
+
+    let import_vm_query = json!({
+        "name": vm.name,
+        "tenant": tenant,
+        "source": source.hostname,
+        "image": image_spec,
+    });
+
+    let mut attempts: Vec<String> = Vec::new();
+    for candidate in candidates.iter() {
+        let placed = match db.begin().await {
+            Ok(mut tx) => match place_vm(&mut tx, &vm.name, &vm.tenant, server_group.as_ref(), candidate, scheduler_config, &request_spec).await {
+                Ok(_) => match Database::set_vm_migration(&mut *tx, &vm.name, &vm.tenant, "migrating", Some(&source.id)).await {
+                    Ok(_) => tx.commit().await.map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+                Err((_, e)) => Err(e),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = placed {
+            attempts.push(format!("{}: {}", &candidate.hostname, e));
+            continue;
+        }
+
+        // A failed migration is cleaned up by the hypervisors themselves.
+        let migrated = if live {
+            live_migrate_vm(source, candidate, &vm.name, tenant, &image_spec, options).await
+        } else {
+            post_to_hypervisor(&candidate.hostname, "/virtualmachine/import", &import_vm_query).await.map(|_| ())
+        };
+
+        if let Err(e) = migrated {
+            attempts.push(format!("{}: {}", &candidate.hostname, e));
+            continue;
+        }
+
+        if let Err(e) = Database::set_vm_migration(db, &vm.name, &vm.tenant, &vm.state, None).await {
+            eprintln!("Failed to update VM '{}' state: {}", &vm.name, e);
+        }
+        if let Err(e) = Database::adjust_hypervisor_usage(db, &source.id, &-vm.ram, &-vm.cpu).await {
+            eprintln!("Failed to update hypervisor '{}': {}", &source.hostname, e);
+        }
+
+        // The source hypervisor drops its copy itself after live migrations.
+        if !live {
+            let delete_vm_query = json!({
+                "name": vm.name,
+                "tenant": tenant,
+            });
+
+            if let Err(e) = post_to_hypervisor(&source.hostname, "/virtualmachine/delete", &delete_vm_query).await {
+                eprintln!("Failed to remove VM '{}' from hypervisor '{}' after migrating it: {}", &vm.name, &source.hostname, e);
+            }
+        }
+
+        return Ok(candidate.hostname.clone());
+    }
+
+    if let Err(e) = Database::update_vm_hypervisor(db, &vm.name, &vm.tenant, &source.id).await {
+        eprintln!("Failed to update VM '{}' hypervisor: {}", &vm.name, e);
+    }
+    if let Err(e) = Database::set_vm_migration(db, &vm.name, &vm.tenant, &vm.state, None).await {
+        eprintln!("Failed to update VM '{}' state: {}", &vm.name, e);
+    }
+    if let Err(e) = Database::release_capacity_claim(db, &vm.name, &vm.tenant).await {
+        eprintln!("Failed to release capacity claim of VM '{}': {}", &vm.name, e);
+    }
+
+    Err((StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to migrate VM '{}': {}", &vm.name, attempts.join("; "))))
+}
+
+// The port is bound to both hypervisors while the guest memory is copied, OVN
+// activates it on the target on the first RARP sent from there. The binding is
+// dropped afterwards, later cold migrations or evacuations move the VM to
+// hypervisors it would not cover.
+async fn live_migrate_vm(
+    source: &HypervisorScheduler,
+    target: &HypervisorScheduler,
+    name: &str,
+    tenant: &str,
+    image_spec: &Option<serde_json::Value>,
+    options: &MigrationOptions
+) -> Result<(), String> {
+    let port_name = format!("{}-{}", tenant, name);
+    set_lsp_requested_chassis(&port_name, &[&source.hostname, &target.hostname], Some("rarp")).await
+        .map_err(|e| format!("Failed to bind port '{}' to both hypervisors: {}", &port_name, e))?;
+
+    let migrate_vm_query = json!({
+        "name": name,
+        "tenant": tenant,
+        "target": target.hostname,
+        "image": image_spec,
+        "bandwidth": options.bandwidth,
+        "auto_converge": options.auto_converge,
+    });
+
+    let migrated = post_to_hypervisor(&source.hostname, "/virtualmachine/migrate", &migrate_vm_query).await;
+    if let Err(e) = set_lsp_requested_chassis(&port_name, &[], None).await {
+        eprintln!("Failed to unbind port '{}' from hypervisors: {}", &port_name, e);
+    }
+
+    migrated.map(|_| ())
+}
+
+async fn flatten_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if vm.base_image.is_none() {
+        return (StatusCode::BAD_REQUEST, format!("VM '{}' is not backed by a base image.", &payload.name)).into_response();
+    }
+
+    if vm.state == "running" {
+        return (StatusCode::CONFLICT, format!("VM '{}' must be shut off to be flattened.", &payload.name)).into_response();
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let flatten_vm_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/virtualmachine/flatten", &flatten_vm_query).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to flatten VM disk: {}", e)).into_response();
+    }
+
+    match Database::set_vm_base_image(&db, &payload.name, &tenant_uuid, None).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' detached from its base image.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)).into_response(),
+    }
+}
+
+async fn resize_vm_handler(headers: HeaderMap, Json(payload): Json<VirtualMachineResize>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let flavor = match (&payload.flavor, payload.cpu, payload.ram) {
+        (Some(name), None, None) => match Database::get_flavor_by_name(&db, name).await {
+            Ok(Some(flavor)) => Some(flavor),
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Flavor '{}' not found.", name)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        (Some(_), _, _) => return (StatusCode::BAD_REQUEST, "VM resize request must include either a flavor or raw cpu and ram values.").into_response(),
+        (None, None, None) => return (StatusCode::BAD_REQUEST, "VM resize request must include a flavor.").into_response(),
+        (None, _, _) if is_admin(&headers) => None,
+        (None, _, _) => return (StatusCode::FORBIDDEN, "Raw cpu and ram values are restricted to admins, please use a flavor.").into_response(),
+    };
+
+    let current_flavor = match vm.flavor {
+        Some(flavor) => match Database::get_flavor_by_id(&db, &flavor).await {
+            Ok(flavor) => flavor,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => None,
+    };
+
+    // Hugepages and pinning are laid out when the domain is defined.
+    let extra_specs = |flavor: Option<&Flavor>| flavor.map(|flavor| (flavor.hugepages, flavor.cpu_pinning)).unwrap_or_default();
+    let (cpu, ram) = match &flavor {
+        Some(flavor) => {
+            if extra_specs(Some(flavor)) != extra_specs(current_flavor.as_ref()) {
+                return (StatusCode::BAD_REQUEST, format!("Flavor '{}' has different extra specs than the current flavor of VM '{}'.", &flavor.name, &payload.name)).into_response();
+            }
+            (flavor.cpu, flavor.ram)
+        },
+        None => (payload.cpu.unwrap_or(vm.cpu), payload.ram.unwrap_or(vm.ram)),
+    };
+
+    if extra_specs(current_flavor.as_ref()).1 && cpu != vm.cpu {
+        return (StatusCode::BAD_REQUEST, format!("VM '{}' has pinned vCPUs, their number cannot be changed.", &payload.name)).into_response();
+    }
+
+    if cpu <= 0 || ram <= 0 {
+        return (StatusCode::BAD_REQUEST, "CPU and RAM must be positive.").into_response();
+    }
+
+    if cpu == vm.cpu && ram == vm.ram {
+        return (StatusCode::BAD_REQUEST, format!("VM '{}' already has {} vCPUs and {}G of RAM.", &payload.name, cpu, ram)).into_response();
+    }
+
+    if let Some(image) = vm.image {
+        match Database::get_image_by_id(&db, &image).await {
+            Ok(Some(image)) if ram < image.min_ram => return (StatusCode::BAD_REQUEST, format!("Image '{}' requires at least {}G of RAM.", &image.name, image.min_ram)).into_response(),
+            Ok(Some(image)) if flavor.as_ref().is_some_and(|flavor| !flavor.archs.contains(&image.arch)) => return (StatusCode::BAD_REQUEST, format!("Flavor is not available for arch '{}'.", &image.arch)).into_response(),
+            Ok(_) => (),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        }
+    }
+
+    let hypervisor = match Database::get_hypervisor_resources(&db, &vm.hypervisor).await {
+        Ok(Some(hypervisor)) => hypervisor,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let scheduler_config = match scheduler::read_conf_file("config.yaml") {
+        Ok(config) => config,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read scheduler configuration: {}", e)).into_response(),
+    };
+
+    // Only the growth has to fit, the VM already holds its current share.
+    // When it does not the VM is migrated to a hypervisor fitting its new
+    // size, whose capacity claim already covers the growth.
+    let ram_delta = ram - vm.ram;
+    let cpu_delta = cpu - vm.cpu;
+    let requested = ResourceUsage { vcpus: cpu_delta.into(), ram: ram_delta.into(), ..Default::default() };
+    let mut hostname = hypervisor.hostname.clone();
+    let mut migrated = false;
+    if !scheduler::capacity(&scheduler_config, &hypervisor).fits(&hypervisor, cpu_delta, ram_delta) {
+        match db.begin().await {
+            Ok(mut tx) => {
+                if let Err(e) = check_quota(&mut tx, &tenant_uuid, &requested).await {
+                    return e.into_response();
+                }
+            },
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        }
+
+        hostname = match migrate_vm(&db, &scheduler_config, &hypervisor, &vm, &payload.tenant, &MigrationOptions::default(), Some((cpu, ram))).await {
+            Ok(hostname) => hostname,
+            Err((status, e)) => return (status, format!("Hypervisor '{}' lacks the resources to resize VM '{}' and it could not be migrated: {}", &hypervisor.hostname, &payload.name, e)).into_response(),
+        };
+        migrated = true;
+    }
+
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &requested).await {
+        return e.into_response();
+    }
+
+    // The new size is recorded under the quota lock and rolled back if the
+    // hypervisor fails, the lock is not held while it is being called.
+    if let Err(e) = Database::update_vm_resources(&mut *tx, &vm.id, &cpu, &ram).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)).into_response();
+    }
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response();
+    }
+
+    let resize_vm_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "cpu": cpu,
+        "memory": ram * 1024,
+    });
+
+    let applied = match post_to_hypervisor(&hostname, "/virtualmachine/resize", &resize_vm_query).await {
+        Ok(applied) => applied,
+        Err(e) => {
+            if let Err(e) = Database::update_vm_resources(&db, &vm.id, &vm.cpu, &vm.ram).await {
+                eprintln!("Failed to restore the size of VM '{}': {}", &payload.name, e);
+            }
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize VM: {}", e)).into_response();
+        },
+    };
+
+    // Raw resizes leave the VM without a matching flavor.
+    if let Err(e) = Database::set_vm_flavor(&db, &payload.name, &tenant_uuid, flavor.as_ref().map(|flavor| &flavor.id)).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)).into_response();
+    }
+
+    if !migrated {
+        if let Err(e) = Database::adjust_hypervisor_usage(&db, &hypervisor.id, &ram_delta, &cpu_delta).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update hypervisor: {}", e)).into_response();
+        }
+    }
+
+    let location = match migrated {
+        true => format!(" on hypervisor '{}'", &hostname),
+        false => String::new(),
+    };
+    match applied.as_str() {
+        "live" => (StatusCode::OK, format!("VM '{}' resized to {} vCPUs and {}G of RAM{}.", &payload.name, cpu, ram, location)).into_response(),
+        _ => (StatusCode::OK, format!("VM '{}' resized to {} vCPUs and {}G of RAM{}, the change applies on next boot.", &payload.name, cpu, ram, location)).into_response(),
+    }
+}
+
+async fn resize_vm_disk_handler(Json(payload): Json<VirtualMachineDiskResize>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if payload.disk_size <= vm.disk_size {
+        return (StatusCode::BAD_REQUEST, format!("Disk of VM '{}' is {}G, it can only be grown.", &payload.name, vm.disk_size)).into_response();
+    }
+
+    // Resizing the file behind an external snapshot overlay would corrupt
+    // the chain, the snapshots have to be deleted first.
+    match Database::count_external_snapshots(&db, &vm.id, Some("vda")).await {
+        Ok(0) => (),
+        Ok(_) => return (StatusCode::BAD_REQUEST, format!("VM '{}' has external snapshots, please delete them first.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &ResourceUsage { disk: (payload.disk_size - vm.disk_size).into(), ..Default::default() }).await {
+        return e.into_response();
+    }
+
+    // Recorded under the quota lock and rolled back if the hypervisor fails.
+    if let Err(e) = Database::update_vm_disk_size(&mut *tx, &vm.id, &payload.disk_size).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)).into_response();
+    }
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update VM in database: {}", e)).into_response();
+    }
+
+    let resize_disk_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "size": payload.disk_size,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/virtualmachine/resize_disk", &resize_disk_query).await {
+        if let Err(e) = Database::update_vm_disk_size(&db, &vm.id, &vm.disk_size).await {
+            eprintln!("Failed to restore the disk size of VM '{}': {}", &payload.name, e);
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize VM disk: {}", e)).into_response();
+    }
+
+    // cloud-init grows the root partition and filesystem on the next boot.
+    (StatusCode::OK, format!("Disk of VM '{}' resized to {}G, the filesystem grows on next boot.", &payload.name, payload.disk_size)).into_response()
+}
+
+async fn create_volume_handler(Json(payload): Json<VolumeCreate>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if payload.name.trim().is_empty() || payload.size <= 0 {
+        return (StatusCode::BAD_REQUEST, "Volume create request must include a non-empty name and a positive size.").into_response();
+    }
+
+    if !is_valid_name(&payload.name) {
+        return (StatusCode::BAD_REQUEST, "Volume names may only contain letters, digits, '.' and '_'.").into_response();
+    }
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::get_volume_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' already exists.", &payload.name)).into_response(),
+        Ok(None) => (),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    // Volumes are local qcow2 files, they live on the hypervisor of the VM
+    // they are meant for or on an explicitly requested one.
+    let hypervisor_uuid = match (&payload.vm, &payload.hypervisor) {
+        (Some(vm), _) => match Database::get_virtual_machine_by_name(&db, vm, &tenant_uuid).await {
+            Ok(Some(vm)) => vm.hypervisor,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", vm)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        (None, Some(hostname)) => match Database::get_hypervisor_by_hostname(&db, hostname).await {
+            Ok(Some(uuid)) => uuid,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", hostname)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        (None, None) => return (StatusCode::BAD_REQUEST, "Volume create request must include either a 'vm' or a 'hypervisor'.").into_response(),
+    };
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &hypervisor_uuid).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &hypervisor_uuid)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &ResourceUsage { volumes: 1, disk: payload.size.into(), ..Default::default() }).await {
+        return e.into_response();
+    }
+
+    // The record counts against the quota once committed, it is removed
+    // again if the hypervisor fails.
+    let volume_uuid = match Database::create_volume(&mut *tx, &payload.name, &tenant_uuid, &payload.size, &hypervisor_uuid).await {
+        Ok(volume_uuid) => volume_uuid,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add volume to database: {}", e)).into_response(),
+    };
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add volume to database: {}", e)).into_response();
+    }
+
+    let create_volume_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "size": payload.size,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/volume/create", &create_volume_query).await {
+        if let Err(e) = Database::delete_volume(&db, &volume_uuid).await {
+            eprintln!("Failed to remove volume '{}' from database: {}", &payload.name, e);
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create volume: {}", e)).into_response();
+    }
+
+    (StatusCode::OK, format!("Volume '{}' created successfully.", &payload.name)).into_response()
+}
+
+async fn delete_volume_handler(Json(payload): Json<VolumeRequest>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let volume = match Database::get_volume_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(volume)) => volume,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if volume.state != "available" {
+        return (StatusCode::BAD_REQUEST, format!("Volume '{}' is attached, please detach it first.", &payload.name)).into_response();
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &volume.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &volume.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let delete_volume_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/volume/delete", &delete_volume_query).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete volume: {}", e)).into_response();
+    }
+
+    match Database::delete_volume(&db, &volume.id).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' deleted successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete volume from database: {}", e)).into_response(),
+    }
+}
+
+async fn attach_volume_handler(Json(payload): Json<VolumeRequest>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let volume = match Database::get_volume_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(volume)) => volume,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if volume.state != "available" {
+        return (StatusCode::BAD_REQUEST, format!("Volume '{}' is already attached.", &payload.name)).into_response();
+    }
+
+    let vm = match &payload.vm {
+        Some(vm) => match Database::get_virtual_machine_by_name(&db, vm, &tenant_uuid).await {
+            Ok(Some(vm)) => vm,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", vm)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => return (StatusCode::BAD_REQUEST, "Volume attach request must include a VM.").into_response(),
+    };
+
+    if vm.hypervisor != volume.hypervisor {
+        return (StatusCode::BAD_REQUEST, format!("Volume '{}' and VM '{}' are on different hypervisors.", &payload.name, &vm.name)).into_response();
+    }
+
+    // vda is the root disk, volumes take the first free virtio target after it.
+    let used_targets: HashSet<String> = match Database::list_vm_volumes(&db, &vm.id).await {
+        Ok(volumes) => volumes.into_iter().filter_map(|volume| volume.target_dev).collect(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let target_dev = match ('b'..='z').map(|c| format!("vd{}", c)).find(|target| !used_targets.contains(target)) {
+        Some(target_dev) => target_dev,
+        None => return (StatusCode::BAD_REQUEST, format!("VM '{}' has no free disk targets left.", &vm.name)).into_response(),
+    };
+
+    // Guests see the serial under /dev/disk/by-id, virtio caps it at 20 characters.
+    let serial = format!("awp-{}", &volume.id.simple().to_string()[..16]);
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &volume.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &volume.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let attach_volume_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "vm": vm.name,
+        "target_dev": target_dev,
+        "serial": serial,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/volume/attach", &attach_volume_query).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to attach volume: {}", e)).into_response();
+    }
+
+    match Database::attach_volume(&db, &volume.id, &vm.id, &target_dev, &serial).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' attached to VM '{}' as {}.", &payload.name, &vm.name, &target_dev)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update volume in database: {}", e)).into_response(),
+    }
+}
+
+async fn resize_volume_handler(Json(payload): Json<VolumeResize>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let volume = match Database::get_volume_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(volume)) => volume,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if payload.size <= volume.size {
+        return (StatusCode::BAD_REQUEST, format!("Volume '{}' is {}G, it can only be grown.", &payload.name, volume.size)).into_response();
+    }
+
+    // Attached volumes are resized through the VM so running guests see the
+    // new size right away.
+    let vm_name = match (volume.vm, &volume.target_dev) {
+        (Some(vm_uuid), Some(target_dev)) => {
+            let vm = match Database::get_virtual_machine_by_id(&db, &vm_uuid).await {
+                Ok(Some(vm)) => vm,
+                Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &vm_uuid)).into_response(),
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            };
+
+            match Database::count_external_snapshots(&db, &vm.id, Some(target_dev)).await {
+                Ok(0) => (),
+                Ok(_) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' is part of external snapshots of VM '{}', please delete them first.", &payload.name, &vm.name)).into_response(),
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            }
+
+            Some(vm.name)
+        },
+        _ => None,
+    };
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &volume.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &volume.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &ResourceUsage { disk: (payload.size - volume.size).into(), ..Default::default() }).await {
+        return e.into_response();
+    }
+
+    // Recorded under the quota lock and rolled back if the hypervisor fails.
+    if let Err(e) = Database::update_volume_size(&mut *tx, &volume.id, &payload.size).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update volume in database: {}", e)).into_response();
+    }
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update volume in database: {}", e)).into_response();
+    }
+
+    let resize_volume_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "vm": vm_name,
+        "size": payload.size,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/volume/resize", &resize_volume_query).await {
+        if let Err(e) = Database::update_volume_size(&db, &volume.id, &volume.size).await {
+            eprintln!("Failed to restore the size of volume '{}': {}", &payload.name, e);
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize volume: {}", e)).into_response();
+    }
+
+    (StatusCode::OK, format!("Volume '{}' resized to {}G.", &payload.name, payload.size)).into_response()
+}
+
+async fn detach_volume_handler(Json(payload): Json<VolumeRequest>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let volume = match Database::get_volume_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(volume)) => volume,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let (vm_uuid, target_dev, serial) = match (volume.vm, &volume.target_dev, &volume.serial) {
+        (Some(vm), Some(target_dev), Some(serial)) => (vm, target_dev, serial),
+        _ => return (StatusCode::BAD_REQUEST, format!("Volume '{}' is not attached.", &payload.name)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_id(&db, &vm_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &vm_uuid)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    // While an external snapshot covers the volume its writes go to an
+    // overlay next to the volume file, which would be lost on detach.
+    match Database::count_external_snapshots(&db, &vm.id, Some(target_dev)).await {
+        Ok(0) => (),
+        Ok(_) => return (StatusCode::BAD_REQUEST, format!("Volume '{}' is part of external snapshots of VM '{}', please delete them first.", &payload.name, &vm.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &volume.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &volume.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let detach_volume_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "vm": vm.name,
+        "target_dev": target_dev,
+        "serial": serial,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, "/volume/detach", &detach_volume_query).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to detach volume: {}", e)).into_response();
+    }
+
+    match Database::detach_volume(&db, &volume.id).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' detached from VM '{}'.", &payload.name, &vm.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update volume in database: {}", e)).into_response(),
+    }
+}
+
+async fn list_volumes_handler(Json(payload): Json<Tenant>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match payload.id {
+        Some(id) => {
+            let volumes = Database::list_volumes(&db, &id).await.unwrap();
+            let volumes = serde_json::to_string(&volumes).unwrap();
+            (StatusCode::OK, volumes).into_response()
+        },
+        _ => (StatusCode::BAD_REQUEST, "Volume list request must include a tenant ID.").into_response(),
+    }
+}
+
+async fn set_quota_handler(headers: HeaderMap, Json(payload): Json<QuotaUpdate>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Quotas can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::set_quota(&db, &tenant_uuid, &payload.quota).await {
+        Ok(_) => (StatusCode::OK, format!("Quota of tenant '{}' updated successfully.", &payload.tenant)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update quota: {}", e)).into_response(),
+    }
+}
+
+async fn quota_usage_handler(Query(query): Query<QuotaQuery>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &query.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let quota = match Database::get_quota(&db, &tenant_uuid).await {
+        Ok(quota) => quota,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::get_resource_usage(&db, &tenant_uuid).await {
+        Ok(usage) => (StatusCode::OK, json!({ "tenant": query.tenant, "limits": quota, "usage": usage }).to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+}
+
+async fn create_server_group_handler(Json(payload): Json<ServerGroupCreate>) -> impl IntoResponse {
+    let policies = ["affinity", "anti-affinity", "soft-affinity", "soft-anti-affinity"];
+    if !policies.contains(&payload.policy.as_str()) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid server group policy. Only {} are supported.", policies.join(", "))).into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::get_server_group_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(None) => (),
+        Ok(Some(_)) => return (StatusCode::CONFLICT, format!("Server group '{}' already exists.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    match Database::create_server_group(&db, &payload.name, &tenant_uuid, &payload.policy).await {
+        Ok(_) => (StatusCode::OK, format!("Server group '{}' created successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add server group to database: {}", e)).into_response(),
+    }
+}
+
+async fn delete_server_group_handler(Json(payload): Json<ServerGroupRequest>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let server_group = match Database::get_server_group_by_name(&db, &payload.name, &tenant_uuid).await {
+        Ok(Some(server_group)) => server_group,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Server group '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::list_server_group_hypervisors(&db, &server_group.id).await {
+        Ok(members) if members.is_empty() => (),
+        Ok(members) => return (StatusCode::CONFLICT, format!("Server group '{}' still has {} VM(s).", &payload.name, members.len())).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    match Database::delete_server_group(&db, &server_group.id).await {
+        Ok(_) => (StatusCode::OK, format!("Server group '{}' deleted successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete server group from database: {}", e)).into_response(),
+    }
+}
+
+async fn list_server_groups_handler(Json(payload): Json<Tenant>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match payload.id {
+        Some(id) => match Database::list_server_groups(&db, &id).await {
+            Ok(server_groups) => (StatusCode::OK, serde_json::to_string(&server_groups).unwrap()).into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to list server groups: {}", e)).into_response(),
+        },
+        _ => (StatusCode::BAD_REQUEST, "Server group list request must include a tenant ID.").into_response(),
+    }
+}
+
+async fn create_flavor_handler(headers: HeaderMap, Json(payload): Json<FlavorCreate>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Flavors can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if let Err(e) = payload.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    match Database::get_flavor_by_name(&db, &payload.name).await {
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, format!("Flavor '{}' already exists.", &payload.name)).into_response(),
+        Ok(None) => (),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    match Database::create_flavor(&db, &payload).await {
+        Ok(_) => (StatusCode::OK, format!("Flavor '{}' created successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add flavor to database: {}", e)).into_response(),
+    }
+}
+
+async fn delete_flavor_handler(headers: HeaderMap, Json(payload): Json<FlavorDelete>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Flavors can only be managed by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let flavor = match Database::get_flavor_by_name(&db, &payload.name).await {
+        Ok(Some(flavor)) => flavor,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Flavor '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::count_flavor_vms(&db, &flavor.id).await {
+        Ok(0) => (),
+        Ok(count) => return (StatusCode::CONFLICT, format!("Flavor '{}' is still used by {} VM(s).", &payload.name, count)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    match Database::delete_flavor(&db, &flavor.id).await {
+        Ok(_) => (StatusCode::OK, format!("Flavor '{}' deleted successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete flavor from database: {}", e)).into_response(),
+    }
+}
+
+async fn list_flavors_handler() -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    match Database::list_flavors(&db).await {
+        Ok(flavors) => (StatusCode::OK, serde_json::to_string(&flavors).unwrap()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to list flavors: {}", e)).into_response(),
+    }
+}
+
+// The catalog is managed by admins, tenants add their own images by capturing VMs.
+async fn create_image_handler(headers: HeaderMap, Json(payload): Json<ImageCreate>) -> impl IntoResponse {
+    if !is_admin(&headers) {
+        return (StatusCode::FORBIDDEN, "Images can only be registered by admins.").into_response();
+    }
+
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if let Err(e) = payload.validate() {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    match Database::get_image(&db, &payload.name, Some(&payload.version), &payload.arch, None).await {
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, format!("Image '{}' version '{}' for '{}' already exists.", &payload.name, &payload.version, &payload.arch)).into_response(),
+        Ok(None) => (),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    match Database::create_image(&db, &payload).await {
+        Ok(_) => (StatusCode::OK, format!("Image '{}' version '{}' for '{}' registered successfully.", &payload.name, &payload.version, &payload.arch)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add image to database: {}", e)).into_response(),
+    }
+}
+
+// Tenants can only delete the images they captured, catalog images and
+// images of other tenants are left to admins.
+async fn delete_image_handler(headers: HeaderMap, Json(payload): Json<ImageDelete>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let image = match Database::get_image(&db, &payload.name, Some(&payload.version), &payload.arch, None).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Image '{}' version '{}' for '{}' not found.", &payload.name, &payload.version, &payload.arch)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if !is_admin(&headers) {
+        let tenant_uuid = match &payload.tenant {
+            Some(tenant) => match Database::get_tenant_by_name(&db, tenant).await {
+                Ok(Some(uuid)) => uuid,
+                Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            },
+            None => return (StatusCode::FORBIDDEN, "Images can only be deleted by their owner tenant or by admins.").into_response(),
+        };
+
+        if image.owner != Some(tenant_uuid) {
+            return (StatusCode::FORBIDDEN, "Images can only be deleted by their owner tenant or by admins.").into_response();
+        }
+    }
+
+    match Database::count_image_vms(&db, &image.id).await {
+        Ok(0) => (),
+        Ok(count) => return (StatusCode::CONFLICT, format!("Image '{}' is still used by {} VM(s).", &payload.name, count)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    if let Err(e) = Database::delete_image(&db, &image.id).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete image from database: {}", e)).into_response();
+    }
+
+    // Hypervisors drop their cached copy, one that cannot be reached or still
+    // has disks backed by it keeps it until its cache evicts it.
+    let file = image.file_name();
+    match Database::list_hypervisors(&db).await {
+        Ok(hypervisors) => {
+            for hypervisor in hypervisors.iter().filter(|hypervisor| hypervisor.cached_images.contains(&file)) {
+                if let Err(e) = post_to_hypervisor(&hypervisor.hostname, "/image/delete", &json!({ "file": file })).await {
+                    eprintln!("Failed to delete cached image '{}' from hypervisor '{}': {}", &file, &hypervisor.hostname, e);
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to list hypervisors caching image '{}': {}", &file, e),
+    }
+
+    (StatusCode::OK, format!("Image '{}' version '{}' for '{}' deleted successfully.", &payload.name, &payload.version, &payload.arch)).into_response()
+}
+
+async fn list_images_handler(Query(query): Query<ImageListQuery>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    // Private images are only listed to their owner tenant.
+    let tenant_uuid = match &query.tenant {
+        Some(tenant) => match Database::get_tenant_by_name(&db, tenant).await {
+            Ok(Some(uuid)) => Some(uuid),
+            Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => None,
+    };
+
+    match Database::list_images(&db, tenant_uuid.as_ref()).await {
+        Ok(images) => (StatusCode::OK, serde_json::to_string(&images).unwrap()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+}
+
+async fn capture_image_handler(Json(payload): Json<ImageCapture>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if let Err(e) = validate_image_name(&payload.name, &payload.version)
+        .and_then(|_| validate_image_visibility(payload.visibility.as_deref())) {
+        return (StatusCode::BAD_REQUEST, e).into_response();
+    }
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.vm, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.vm)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if vm.state != "shutoff" {
+        return (StatusCode::CONFLICT, format!("VM '{}' must be shut off to be captured.", &payload.vm)).into_response();
+    }
+
+    // The new image inherits what the VM was created from, besides its disk size.
+    let source = match vm.image {
+        Some(image) => match Database::get_image_by_id(&db, &image).await {
+            Ok(Some(source)) => source,
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("Source image of VM '{}' not found.", &payload.vm)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => return (StatusCode::BAD_REQUEST, format!("VM '{}' was not created from a catalog image.", &payload.vm)).into_response(),
+    };
+
+    match Database::get_image(&db, &payload.name, Some(&payload.version), &source.arch, None).await {
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, format!("Image '{}' version '{}' for '{}' already exists.", &payload.name, &payload.version, &source.arch)).into_response(),
+        Ok(None) => (),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let file = image_file_name(&payload.name, &payload.version, &source.arch, "qcow2");
+    let capture_image_query = json!({
+        "vm": payload.vm,
+        "tenant": payload.tenant,
+        "file": file,
+        "sysprep": payload.sysprep,
+    });
+
+    let checksum = match post_to_hypervisor(&hypervisor_hostname, "/image/capture", &capture_image_query).await {
+        Ok(checksum) => checksum,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to capture image: {}", e)).into_response(),
+    };
+
+    // Other hypervisors fetch the image from the controlplane image store.
+    if let Err(e) = download_from_hypervisor(&hypervisor_hostname, &file).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to copy image to the image store: {}", e)).into_response();
+    }
+
+    let image = ImageCreate {
+        name: payload.name.clone(),
+        version: payload.version.clone(),
+        arch: source.arch,
+        format: "qcow2".to_string(),
+        checksum,
+        min_disk: vm.disk_size,
+        min_ram: source.min_ram,
+        default_user: source.default_user,
+        cloud_init: Some(source.cloud_init),
+        visibility: Some(payload.visibility.unwrap_or("private".to_string())),
+        owner: Some(tenant_uuid),
+    };
+
+    match Database::create_image(&db, &image).await {
+        Ok(_) => (StatusCode::OK, format!("Image '{}' version '{}' captured from VM '{}' successfully.", &payload.name, &payload.version, &payload.vm)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add image to database: {}", e)).into_response(),
+    }
+}
+
+fn image_store_path() -> Result<String, String> {
+    let config = std::fs::read_to_string("config.yaml").map_err(|e| format!("Failed to read config file: {}", e))?;
+    let config: ImageStoreConfig = serde_yaml::from_str(&config).map_err(|e| format!("Failed to parse config file: {}", e))?;
+    Ok(config.images.path.trim_end_matches('/').to_string())
+}
+
+async fn download_from_hypervisor(hostname: &str, file: &str) -> Result<(), String> {
+    let path = format!("{}/{}", image_store_path()?, file);
+    let partial = format!("{}.part", path);
+
+    let mut response = Client::new().get(format!("http://{}:3000/image/download/{}", hostname, file))
+        .header("X-AWP-Agent-Token", agent_token().unwrap_or_default())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Failed to download image from hypervisor '{}': {}", hostname, e))?;
+
+    let mut output = tokio::fs::File::create(&partial).await.map_err(|e| e.to_string())?;
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        output.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+    output.flush().await.map_err(|e| e.to_string())?;
+
+    tokio::fs::rename(&partial, &path).await.map_err(|e| e.to_string())
+}
+
+// Hypervisors fetch base images from here unless they are pointed at a
+// separate image store. Only files of registered images are served, private
+// ones to hypervisors, admins and their owner tenant only.
+async fn download_image_handler(headers: HeaderMap, Path(file): Path<String>, Query(query): Query<ImageListQuery>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let image = match Database::get_image_by_file(&db, &file).await {
+        Ok(Some(image)) => image,
+        Ok(None) => return (StatusCode::NOT_FOUND, format!("Image '{}' is not registered.", &file)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if image.visibility == "private" && !is_agent(&headers) && !is_admin(&headers) {
+        let tenant_uuid = match &query.tenant {
+            Some(tenant) => match Database::get_tenant_by_name(&db, tenant).await {
+                Ok(tenant_uuid) => tenant_uuid,
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+            },
+            None => None,
+        };
+
+        if tenant_uuid.is_none() || tenant_uuid != image.owner {
+            return (StatusCode::NOT_FOUND, format!("Image '{}' is not registered.", &file)).into_response();
+        }
+    }
+
+    let image_store = match image_store_path() {
+        Ok(image_store) => image_store,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    };
+
+    let image_file = match tokio::fs::File::open(format!("{}/{}", image_store, &file)).await {
+        Ok(image_file) => image_file,
+        Err(e) => return (StatusCode::NOT_FOUND, format!("Image '{}' is not available: {}", &file, e)).into_response(),
+    };
+
+    (StatusCode::OK, Body::from_stream(ReaderStream::new(image_file))).into_response()
+}
+
+async fn create_snapshot_handler(Json(payload): Json<SnapshotCreate>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    if !is_valid_name(&payload.name) {
+        return (StatusCode::BAD_REQUEST, "Snapshot names may only contain letters, digits, '.' and '_'.").into_response();
+    }
+
+    let kind = payload.kind.clone().unwrap_or("internal".to_string());
+    match kind.as_str() {
+        "internal" if payload.quiesce => return (StatusCode::BAD_REQUEST, "Internal snapshots capture the VM memory, quiesce is only supported for external snapshots.").into_response(),
+        "internal" | "external" => (),
+        _ => return (StatusCode::BAD_REQUEST, "Invalid snapshot kind specified. Only 'internal' and 'external' are supported.").into_response(),
+    }
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.vm, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.vm)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    match Database::get_snapshot_by_name(&db, &payload.name, &vm.id).await {
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, format!("Snapshot '{}' already exists.", &payload.name)).into_response(),
+        Ok(None) => (),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let mut tx = match db.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    if let Err(e) = check_quota(&mut tx, &tenant_uuid, &ResourceUsage { snapshots: 1, ..Default::default() }).await {
+        return e.into_response();
+    }
+
+    // The record counts against the quota once committed, it is removed
+    // again if the hypervisor fails and gets its disks once it answers.
+    let snapshot_uuid = match Database::create_snapshot(&mut *tx, &payload.name, &tenant_uuid, &vm.id, &kind, &payload.quiesce, &[]).await {
+        Ok(snapshot_uuid) => snapshot_uuid,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add snapshot to database: {}", e)).into_response(),
+    };
+
+    if let Err(e) = tx.commit().await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add snapshot to database: {}", e)).into_response();
+    }
+
+    let create_snapshot_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "vm": payload.vm,
+        "kind": kind,
+        "quiesce": payload.quiesce,
+    });
+
+    // The hypervisor answers with the disk targets included in the snapshot.
+    let disks: Result<Vec<String>, String> = match post_to_hypervisor(&hypervisor_hostname, "/snapshot/create", &create_snapshot_query).await {
+        Ok(body) => serde_json::from_str(&body)
+            .map_err(|e| format!("Snapshot created but the hypervisor response '{}' is invalid: {}", body, e)),
+        Err(e) => Err(format!("Failed to create snapshot: {}", e)),
+    };
+
+    let disks = match disks {
+        Ok(disks) => disks,
+        Err(e) => {
+            if let Err(e) = Database::delete_snapshot(&db, &snapshot_uuid).await {
+                eprintln!("Failed to remove snapshot '{}' from database: {}", &payload.name, e);
+            }
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        },
+    };
+
+    match Database::set_snapshot_disks(&db, &snapshot_uuid, &disks).await {
+        Ok(_) => (StatusCode::OK, format!("Snapshot '{}' of VM '{}' created successfully.", &payload.name, &payload.vm)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to add snapshot to database: {}", e)).into_response(),
+    }
+}
+
+async fn revert_snapshot_handler(Json(payload): Json<SnapshotRequest>) -> impl IntoResponse {
+    snapshot_action(payload, "revert").await
+}
+
+async fn delete_snapshot_handler(Json(payload): Json<SnapshotRequest>) -> impl IntoResponse {
+    snapshot_action(payload, "delete").await
+}
+
+async fn snapshot_action(payload: SnapshotRequest, action: &str) -> axum::response::Response {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm = match Database::get_virtual_machine_by_name(&db, &payload.vm, &tenant_uuid).await {
+        Ok(Some(vm)) => vm,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", &payload.vm)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let snapshot = match Database::get_snapshot_by_name(&db, &payload.name, &vm.id).await {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Snapshot '{}' not found.", &payload.name)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let hypervisor_hostname = match Database::get_hypervisor_by_id(&db, &vm.hypervisor).await {
+        Ok(Some(hostname)) => hostname,
+        Ok(None) => return (StatusCode::BAD_REQUEST, format!("Hypervisor '{}' not found.", &vm.hypervisor)).into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let snapshot_query = json!({
+        "name": payload.name,
+        "tenant": payload.tenant,
+        "vm": payload.vm,
+    });
+
+    if let Err(e) = post_to_hypervisor(&hypervisor_hostname, &format!("/snapshot/{}", action), &snapshot_query).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to {} snapshot: {}", action, e)).into_response();
+    }
+
+    if action == "revert" {
+        return (StatusCode::OK, format!("VM '{}' reverted to snapshot '{}'.", &payload.vm, &payload.name)).into_response();
+    }
+
+    match Database::delete_snapshot(&db, &snapshot.id).await {
+        Ok(_) => (StatusCode::OK, format!("Snapshot '{}' deleted successfully.", &payload.name)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete snapshot from database: {}", e)).into_response(),
+    }
+}
+
+async fn list_snapshots_handler(Json(payload): Json<SnapshotList>) -> impl IntoResponse {
+    let db = match Database::new().await {
+        Ok(db) => db,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Database connection error").into_response();
+        }
+    };
+
+    let tenant_uuid = match Database::get_tenant_by_name(&db, &payload.tenant).await {
+        Ok(Some(uuid)) => uuid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, "Tenant not found").into_response(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    };
+
+    let vm_uuid = match &payload.vm {
+        Some(vm) => match Database::get_virtual_machine_by_name(&db, vm, &tenant_uuid).await {
+            Ok(Some(vm)) => Some(vm.id),
+            Ok(None) => return (StatusCode::BAD_REQUEST, format!("VM '{}' not found.", vm)).into_response(),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+        },
+        None => None,
+    };
+
+    match Database::list_snapshots(&db, &tenant_uuid, vm_uuid).await {
+        Ok(snapshots) => (StatusCode::OK, serde_json::to_string(&snapshots).unwrap()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response(),
+    }
+}
+
+async fn post_to_hypervisor(hostname: &str, path: &str, query: &serde_json::Value) -> Result<String, String> {
+    let client = Client::new();
+    let response = client.post(format!("http://{}:3000{}", hostname, path))
+        .header("Content-Type", "application/json")
+        .body(query.to_string())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to connect to hypervisor compute API '{}': {}", hostname, e))?;
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if status != StatusCode::OK {
+        return Err(format!("Hypervisor '{}' returned {}: {}", hostname, status, body));
+    }
+
+    Ok(body)
+}
+
 async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "404 - Not Found")
 }
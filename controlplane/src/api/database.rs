@@ -3,12 +3,17 @@
 
 use sqlx::postgres::PgPoolOptions;
 use sqlx::types::{Uuid,ipnetwork::IpNetwork};
-use crate::api::{Port, Tenant, Vpc, SSHKey, HypervisorScheduler, VirtualMachine, ProviderNetwork};
+use crate::api::{Port, Tenant, Vpc, SSHKey, HypervisorAgent, HypervisorScheduler, HypervisorOvercommit, HypervisorLabels, VirtualMachine, VirtualMachineQos, ProviderNetwork, Volume, Snapshot, Image, ImageCreate, Flavor, FlavorCreate, ServerGroup, Quota, ResourceUsage};
 use std::env;
 use std::path::Path;
 
 pub struct Database {}
 
+// Hypervisor columns plus the capacity claimed by VMs still being created.
+const HYPERVISOR_CLAIMS_COLUMNS: &str = "h.*, 
+    (SELECT COALESCE(SUM(c.cpu), 0) FROM capacity_claims c WHERE c.hypervisor = h.id)::integer AS claimed_cpu, 
+    (SELECT COALESCE(SUM(c.ram), 0) FROM capacity_claims c WHERE c.hypervisor = h.id)::integer AS claimed_ram";
+
 #[derive(serde::Deserialize, Debug)]
 struct Config {
     controlplane: ControlPlaneAPI,
@@ -95,17 +100,17 @@ impl Database {
     }
 
     pub async fn create_vpc(
-        pool: &sqlx::Pool<sqlx::Postgres>, 
+        executor: impl sqlx::PgExecutor<'_>, 
         name: &str, 
         cidr: &str,
         nat: &bool,
         tenant: &Uuid
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query!("INSERT INTO vpcs (name, cidr, nat, tenant) VALUES ($1, $2, $3, $4)", name, cidr, nat, tenant)
-            .execute(pool)
+    ) -> Result<Uuid, sqlx::Error> {
+        let row = sqlx::query!("INSERT INTO vpcs (name, cidr, nat, tenant) VALUES ($1, $2, $3, $4) RETURNING id", name, cidr, nat, tenant)
+            .fetch_one(executor)
             .await?;
     
-        Ok(())
+        Ok(row.id)
     }
 
     pub async fn delete_vpc(
@@ -242,7 +247,7 @@ impl Database {
         hosted_vms: i32
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "UPDATE hypervisors SET used_ram = $1, used_cpu = $2, hosted_vms = $3 WHERE id = $4", 
+            "UPDATE hypervisors SET used_ram = $1, used_cpu = $2, hosted_vms = $3, last_seen = now(), status = 'up' WHERE id = $4", 
             used_memory, 
             used_cpu, 
             hosted_vms, 
@@ -253,6 +258,77 @@ impl Database {
         Ok(())
     }
 
+    pub async fn update_hypervisors_status(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        suspect_after: &i32, 
+        down_after: &i32
+    ) -> Result<Vec<(String, String)>, sqlx::Error> {
+        let rows = sqlx::query!(
+            "UPDATE hypervisors h SET status = s.status FROM (
+                SELECT id, CASE 
+                    WHEN last_seen < now() - make_interval(secs => $2) THEN 'down' 
+                    WHEN last_seen < now() - make_interval(secs => $1) THEN 'suspect' 
+                    ELSE 'up' END AS status 
+                FROM hypervisors) s 
+             WHERE h.id = s.id AND h.status <> s.status 
+             RETURNING h.hostname, h.status", 
+            f64::from(*suspect_after), 
+            f64::from(*down_after))
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| (r.hostname, r.status)).collect())
+    }
+
+    pub async fn mark_down_hypervisors_vms(
+        pool: &sqlx::Pool<sqlx::Postgres>
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE vms SET state = 'unknown' FROM hypervisors h 
+             WHERE vms.hypervisor = h.id AND h.status = 'down' AND vms.state NOT IN ('creating', 'unknown')")
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn set_hypervisor_maintenance(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        hostname: &str, 
+        maintenance: bool
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE hypervisors SET maintenance = $1 WHERE hostname = $2", 
+            maintenance, 
+            hostname)
+            .execute(pool)
+            .await?;
+    
+        Ok(result.rows_affected())
+    }
+
+    // Zone and labels set through the API take precedence over the ones the
+    // agent reports, those the agent leaves out are left as they are.
+    pub async fn update_hypervisor_inventory(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        agent: &HypervisorAgent,
+        labels: Option<&[String]>
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE hypervisors SET cached_images = $1, cpu_model = COALESCE($2, cpu_model), 
+                availability_zone = CASE WHEN availability_zone_by_admin THEN availability_zone ELSE COALESCE($3, availability_zone) END, 
+                labels = CASE WHEN labels_by_admin THEN labels ELSE COALESCE($4, labels) END WHERE hostname = $5", 
+            &agent.images, 
+            agent.cpu_model,
+            agent.availability_zone,
+            labels,
+            agent.hostname)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
     pub async fn get_vpc_by_name(
         pool: &sqlx::Pool<sqlx::Postgres>,
         name: &str,
@@ -316,6 +392,17 @@ impl Database {
         Ok(rows)
     }
 
+    pub async fn get_ssh_key_name(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<String>, sqlx::Error> {
+        let row = sqlx::query!("SELECT name FROM ssh_pub_keys where id = $1", id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.map(|r| r.name))
+    }
+
     pub async fn get_ssh_key(
         pool: &sqlx::Pool<sqlx::Postgres>, 
         name: &str
@@ -328,27 +415,146 @@ impl Database {
     }
 
     pub async fn list_hypervisors(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<Vec<HypervisorScheduler>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, HypervisorScheduler>("SELECT * FROM hypervisors")
+        let rows = sqlx::query_as::<_, HypervisorScheduler>(&format!("SELECT {} FROM hypervisors h", HYPERVISOR_CLAIMS_COLUMNS))
         .fetch_all(pool)
         .await?;
 
         Ok(rows)
     }
 
-    pub async fn get_hypervisors_min_hosted_vms(
+    pub async fn set_hypervisor_overcommit(
         pool: &sqlx::Pool<sqlx::Postgres>, 
-        arch: &str
-    ) -> Result<Vec<HypervisorScheduler>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, HypervisorScheduler>("SELECT * FROM hypervisors WHERE arch = $1 AND hosted_vms = (SELECT MIN(hosted_vms) FROM hypervisors WHERE arch = $1);")
-        .bind(arch)
-        .fetch_all(pool)
-        .await?;
+        overcommit: &HypervisorOvercommit
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE hypervisors SET cpu_allocation_ratio = COALESCE($1, cpu_allocation_ratio), ram_allocation_ratio = COALESCE($2, ram_allocation_ratio), 
+             reserved_cpu = COALESCE($3, reserved_cpu), reserved_ram = COALESCE($4, reserved_ram) WHERE hostname = $5", 
+            overcommit.cpu_allocation_ratio, 
+            overcommit.ram_allocation_ratio, 
+            overcommit.reserved_cpu, 
+            overcommit.reserved_ram, 
+            overcommit.hostname)
+            .execute(pool)
+            .await?;
+    
+        Ok(result.rows_affected())
+    }
 
-        Ok(rows)
+    pub async fn set_hypervisor_labels(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        labels: &HypervisorLabels
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query!(
+            "UPDATE hypervisors SET availability_zone = COALESCE($1, availability_zone), labels = COALESCE($2, labels), 
+                availability_zone_by_admin = availability_zone_by_admin OR $1 IS NOT NULL, labels_by_admin = labels_by_admin OR $2 IS NOT NULL 
+             WHERE hostname = $3", 
+            labels.availability_zone, 
+            labels.labels.as_deref(), 
+            labels.hostname)
+            .execute(pool)
+            .await?;
+    
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_hypervisor_resources(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<HypervisorScheduler>, sqlx::Error> {
+        let row = sqlx::query_as::<_, HypervisorScheduler>(&format!("SELECT {} FROM hypervisors h WHERE h.id = $1", HYPERVISOR_CLAIMS_COLUMNS))
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn lock_hypervisor(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: &Uuid
+    ) -> Result<Option<HypervisorScheduler>, sqlx::Error> {
+        sqlx::query!("SELECT id FROM hypervisors WHERE id = $1 FOR UPDATE", id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        let row = sqlx::query_as::<_, HypervisorScheduler>(&format!("SELECT {} FROM hypervisors h WHERE h.id = $1", HYPERVISOR_CLAIMS_COLUMNS))
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn release_capacity_claim(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        tenant: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM capacity_claims WHERE vm = (SELECT id FROM vms WHERE name = $1 AND tenant = $2)", 
+            name, tenant)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn claim_capacity(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str, 
+        tenant: &Uuid, 
+        hypervisor: &Uuid,
+        cpu: &i32,
+        ram: &i32
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO capacity_claims (vm, hypervisor, cpu, ram) 
+             SELECT id, $3, $4, $5 FROM vms WHERE name = $1 AND tenant = $2
+             ON CONFLICT (vm) DO UPDATE SET hypervisor = EXCLUDED.hypervisor, cpu = EXCLUDED.cpu, ram = EXCLUDED.ram, created_at = now()", 
+            name, tenant, hypervisor, cpu, ram)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    // Claims of VMs reported by the agent are dropped, their resources are in
+    // the used counters now. Claims of VMs no longer being created that the
+    // agent never reported expire after a while.
+    pub async fn reconcile_capacity_claims(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        hypervisor: &Uuid, 
+        domains: &[String]
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "DELETE FROM capacity_claims c USING vms v, tenants t 
+             WHERE c.vm = v.id AND v.tenant = t.id AND c.hypervisor = $1 
+             AND (t.name || '-' || v.name = ANY($2) OR (v.state <> 'creating' AND c.created_at < now() - interval '10 minutes'))", 
+            hypervisor, domains)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    // Keeps the counters right until the agent reports the actual usage.
+    pub async fn adjust_hypervisor_usage(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid, 
+        ram: &i32, 
+        cpu: &i32
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE hypervisors SET used_ram = used_ram + $1, used_cpu = used_cpu + $2 WHERE id = $3", 
+            ram, cpu, id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
     }
 
     pub async fn create_virtual_machine(
-        pool: &sqlx::Pool<sqlx::Postgres>,
+        executor: impl sqlx::PgExecutor<'_>,
         name: &str,
         cpu: &i32,
         ram: &i32,
@@ -361,10 +567,12 @@ impl Database {
         state: &str,
         networking: &str,
         network: Option<String>,
+        static_ip: Option<IpNetwork>,
+        image: &Uuid,
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
-            "INSERT INTO vms (name, cpu, ram, tenant, vpc, ssh_pub_key, disk_size, hypervisor, os, state, networking, network) 
-             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            "INSERT INTO vms (name, cpu, ram, tenant, vpc, ssh_pub_key, disk_size, hypervisor, os, state, networking, network, static_ip, image) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
             name,
             cpu,
             ram,
@@ -376,9 +584,11 @@ impl Database {
             os,
             state,
             networking,
-            network
+            network,
+            static_ip,
+            image
         )
-        .execute(pool)
+        .execute(executor)
         .await?;
     
         Ok(())
@@ -399,6 +609,19 @@ impl Database {
         Ok(row)
     }
 
+    pub async fn get_virtual_machine_by_id(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<VirtualMachine>, sqlx::Error> {
+        let row = sqlx::query_as::<_, VirtualMachine>(
+            "SELECT * FROM vms where id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
     pub async fn get_virtual_machine_by_tenant(
         pool: &sqlx::Pool<sqlx::Postgres>, 
         tenant: &Uuid
@@ -412,11 +635,55 @@ impl Database {
         Ok(row)
     }
 
+    pub async fn list_hypervisor_vms(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        hypervisor: &Uuid
+    ) -> Result<Vec<VirtualMachine>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, VirtualMachine>("SELECT * FROM vms WHERE hypervisor = $1 AND state <> 'creating'")
+            .bind(hypervisor)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn set_vm_placement(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str, 
+        tenant: &Uuid, 
+        availability_zone: Option<&str>,
+        label_selector: &[String]
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET availability_zone = $1, label_selector = $2 WHERE name = $3 AND tenant = $4", 
+            availability_zone, label_selector, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn set_vm_mac_addr(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str, 
+        tenant: &Uuid, 
+        mac_addr: &str
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET mac_addr = $1 WHERE name = $2 AND tenant = $3", 
+            mac_addr, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
     pub async fn delete_virtual_machine(
         pool: &sqlx::Pool<sqlx::Postgres>, 
-        name: &str
+        name: &str,
+        tenant: &Uuid
     ) -> Result<(), sqlx::Error> {
-        sqlx::query!("DELETE FROM vms where name = $1", name)
+        sqlx::query!("DELETE FROM vms where name = $1 AND tenant = $2", name, tenant)
             .execute(pool)
             .await?;
     
@@ -435,19 +702,135 @@ impl Database {
         Ok(rows)
     }
 
+    // The source hypervisor is kept while the VM is migrating, it is the one
+    // reporting the progress.
+    pub async fn set_vm_migration(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        state: &str,
+        migration_source: Option<&Uuid>
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET state = $1, migration_source = $2 WHERE name = $3 AND tenant = $4", 
+            state, migration_source, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
     pub async fn update_vm_state(
-        pool: &sqlx::Pool<sqlx::Postgres>, 
+        executor: impl sqlx::PgExecutor<'_>, 
         name: &str, 
         tenant: &Uuid, 
         state: &str
     ) -> Result<(), sqlx::Error> {
         sqlx::query!("UPDATE vms SET state = $1 WHERE name = $2 AND tenant = $3", state, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn update_vm_qos(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        qos: &VirtualMachineQos
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET ingress_kbps = $1, egress_kbps = $2, burst_kbit = $3 WHERE name = $4 AND tenant = $5", 
+            qos.ingress_kbps, qos.egress_kbps, qos.burst_kbit, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn update_vm_resources(
+        executor: impl sqlx::PgExecutor<'_>, 
+        id: &Uuid, 
+        cpu: &i32, 
+        ram: &i32
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE vms SET cpu = $1, ram = $2 WHERE id = $3", cpu, ram, id)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn set_vm_flavor(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        flavor: Option<&Uuid>
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE vms SET flavor = $1 WHERE name = $2 AND tenant = $3", flavor, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn update_vm_disk_size(
+        executor: impl sqlx::PgExecutor<'_>, 
+        id: &Uuid, 
+        disk_size: &i32
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE vms SET disk_size = $1 WHERE id = $2", disk_size, id)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn update_vm_hypervisor(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str, 
+        tenant: &Uuid, 
+        hypervisor: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET hypervisor = $1 WHERE name = $2 AND tenant = $3", 
+            hypervisor, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn add_vm_scheduling_attempt(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        tenant: &Uuid, 
+        attempt: &str
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET scheduling_attempts = array_append(scheduling_attempts, $1) WHERE name = $2 AND tenant = $3", 
+            attempt, name, tenant)
             .execute(pool)
             .await?;
     
         Ok(())
     }
 
+    pub async fn set_vm_base_image(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        base_image: Option<&str>
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET base_image = $1 WHERE name = $2 AND tenant = $3", 
+            base_image, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
     pub async fn get_provider_network(
         pool: &sqlx::Pool<sqlx::Postgres>,
         name: &str
@@ -472,18 +855,44 @@ impl Database {
     }
 
     pub async fn create_provider_network(
-        pool: &sqlx::Pool<sqlx::Postgres>, 
-        name: &str,
-        vlan: &i32, 
-        subnet: &str
+        executor: impl sqlx::PgExecutor<'_>, 
+        network: &ProviderNetwork
     ) -> Result<(), sqlx::Error> {
-        sqlx::query!("INSERT INTO provider_networks (name, vlan, subnet) VALUES ($1, $2, $3)", name, vlan, subnet)
-            .execute(pool)
+        sqlx::query!(
+            "INSERT INTO provider_networks (name, vlan, subnet, gateway, allocation_start, allocation_end, dns_servers, dhcp) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            network.name,
+            network.vlan,
+            network.subnet,
+            network.gateway,
+            network.allocation_start,
+            network.allocation_end,
+            &network.dns_servers,
+            network.dhcp.unwrap_or(true)
+        )
+            .execute(executor)
             .await?;
     
         Ok(())
     }
 
+    // Serializes address allocations on the network until the transaction
+    // ends, the address is reserved by the VM record inserted under the lock.
+    pub async fn lock_provider_network_static_ips(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, 
+        network: &str
+    ) -> Result<Vec<IpNetwork>, sqlx::Error> {
+        sqlx::query!("SELECT name FROM provider_networks WHERE name = $1 FOR UPDATE", network)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        let rows = sqlx::query!("SELECT static_ip FROM vms WHERE network = $1 AND static_ip IS NOT NULL", network)
+            .fetch_all(&mut **tx)
+            .await?;
+
+        Ok(rows.into_iter().filter_map(|r| r.static_ip).collect())
+    }
+
     pub async fn delete_provider_network(
         pool: &sqlx::Pool<sqlx::Postgres>, 
         name: &str
@@ -507,4 +916,561 @@ impl Database {
     
         Ok(())
     }
+
+    pub async fn create_volume(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        size: &i32, 
+        hypervisor: &Uuid
+    ) -> Result<Uuid, sqlx::Error> {
+        let row = sqlx::query!(
+            "INSERT INTO volumes (name, tenant, size, hypervisor, state) VALUES ($1, $2, $3, $4, 'available') RETURNING id", 
+            name, tenant, size, hypervisor)
+            .fetch_one(executor)
+            .await?;
+    
+        Ok(row.id)
+    }
+
+    pub async fn get_volume_by_name(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        tenant: &Uuid
+    ) -> Result<Option<Volume>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Volume>("SELECT * FROM volumes WHERE name = $1 AND tenant = $2")
+            .bind(name)
+            .bind(tenant)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn list_volumes(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        tenant: &Uuid
+    ) -> Result<Vec<Volume>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Volume>("SELECT * FROM volumes WHERE tenant = $1")
+            .bind(tenant)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn list_vm_volumes(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        vm: &Uuid
+    ) -> Result<Vec<Volume>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Volume>("SELECT * FROM volumes WHERE vm = $1")
+            .bind(vm)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn update_volume_size(
+        executor: impl sqlx::PgExecutor<'_>, 
+        id: &Uuid, 
+        size: &i32
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE volumes SET size = $1 WHERE id = $2", size, id)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn attach_volume(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid, 
+        vm: &Uuid, 
+        target_dev: &str, 
+        serial: &str
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE volumes SET state = 'attached', vm = $1, target_dev = $2, serial = $3 WHERE id = $4", 
+            vm, target_dev, serial, id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn detach_volume(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE volumes SET state = 'available', vm = NULL, target_dev = NULL WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn detach_vm_volumes(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        vm: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE volumes SET state = 'available', vm = NULL, target_dev = NULL WHERE vm = $1", vm)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn delete_volume(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM volumes WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn create_snapshot(
+        executor: impl sqlx::PgExecutor<'_>, 
+        name: &str, 
+        tenant: &Uuid, 
+        vm: &Uuid, 
+        kind: &str, 
+        quiesced: &bool, 
+        disks: &[String]
+    ) -> Result<Uuid, sqlx::Error> {
+        let row = sqlx::query!(
+            "INSERT INTO snapshots (name, tenant, vm, kind, quiesced, disks) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id", 
+            name, tenant, vm, kind, quiesced, disks)
+            .fetch_one(executor)
+            .await?;
+    
+        Ok(row.id)
+    }
+
+    pub async fn set_snapshot_disks(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid, 
+        disks: &[String]
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE snapshots SET disks = $1 WHERE id = $2", disks, id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn get_snapshot_by_name(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        vm: &Uuid
+    ) -> Result<Option<Snapshot>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Snapshot>(
+            "SELECT * FROM snapshots WHERE name = $1 AND vm = $2")
+            .bind(name)
+            .bind(vm)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn list_snapshots(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        tenant: &Uuid, 
+        vm: Option<Uuid>
+    ) -> Result<Vec<Snapshot>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Snapshot>(
+            "SELECT * FROM snapshots WHERE tenant = $1 AND ($2::uuid IS NULL OR vm = $2) ORDER BY created_at")
+            .bind(tenant)
+            .bind(vm)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn count_external_snapshots(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        vm: &Uuid, 
+        disk: Option<&str>
+    ) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query!(
+            "SELECT COUNT(*) AS count FROM snapshots WHERE vm = $1 AND kind = 'external' AND ($2::text IS NULL OR $2 = ANY(disks))", 
+            vm, disk)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(row.count.unwrap_or(0))
+    }
+
+    pub async fn delete_snapshot(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM snapshots WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn create_image(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        image: &ImageCreate
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO images (name, version, arch, format, checksum, min_disk, min_ram, default_user, cloud_init, owner, visibility) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)", 
+            image.name, image.version, image.arch, image.format, image.checksum,
+            image.min_disk, image.min_ram, image.default_user, image.cloud_init.unwrap_or(true),
+            image.owner, image.visibility.as_deref().unwrap_or("public"))
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    // Without an explicit version the most recently registered one wins.
+    // With a tenant only public images and the tenant private ones match.
+    pub async fn get_image(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        version: Option<&str>, 
+        arch: &str,
+        tenant: Option<&Uuid>
+    ) -> Result<Option<Image>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Image>(
+            "SELECT * FROM images WHERE name = $1 AND ($2::varchar IS NULL OR version = $2) AND arch = $3 
+             AND ($4::uuid IS NULL OR visibility = 'public' OR owner = $4) ORDER BY created_at DESC LIMIT 1")
+            .bind(name)
+            .bind(version)
+            .bind(arch)
+            .bind(tenant)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn get_image_by_id(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<Image>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Image>(
+            "SELECT * FROM images WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn get_image_by_file(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        file: &str
+    ) -> Result<Option<Image>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Image>(
+            "SELECT * FROM images WHERE COALESCE(file, name || '-' || version || '-' || arch || '.' || format) = $1")
+            .bind(file)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn list_images(
+        pool: &sqlx::Pool<sqlx::Postgres>,
+        tenant: Option<&Uuid>
+    ) -> Result<Vec<Image>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Image>(
+            "SELECT * FROM images WHERE visibility = 'public' OR owner = $1 ORDER BY name, arch, created_at")
+            .bind(tenant)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn count_image_vms(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        image: &Uuid
+    ) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query!("SELECT COUNT(*) AS count FROM vms WHERE image = $1", image)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(row.count.unwrap_or(0))
+    }
+
+    pub async fn delete_image(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM images WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn create_flavor(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        flavor: &FlavorCreate
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO flavors (name, cpu, ram, disk_size, ingress_kbps, egress_kbps, burst_kbit, archs, hugepages, cpu_pinning) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)", 
+            flavor.name, flavor.cpu, flavor.ram, flavor.disk_size,
+            flavor.qos.ingress_kbps, flavor.qos.egress_kbps, flavor.qos.burst_kbit,
+            &flavor.archs(), flavor.hugepages, flavor.cpu_pinning)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn get_flavor_by_name(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str
+    ) -> Result<Option<Flavor>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Flavor>("SELECT * FROM flavors WHERE name = $1")
+            .bind(name)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn get_flavor_by_id(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<Flavor>, sqlx::Error> {
+        let row = sqlx::query_as::<_, Flavor>("SELECT * FROM flavors WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn list_flavors(
+        pool: &sqlx::Pool<sqlx::Postgres>
+    ) -> Result<Vec<Flavor>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, Flavor>("SELECT * FROM flavors ORDER BY cpu, ram, disk_size")
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn count_flavor_vms(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        flavor: &Uuid
+    ) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query!("SELECT COUNT(*) AS count FROM vms WHERE flavor = $1", flavor)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(row.count.unwrap_or(0))
+    }
+
+    pub async fn delete_flavor(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM flavors WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    // Tenants without a quota row get one without limits, the row lock is
+    // held until the transaction ends.
+    pub async fn lock_quota(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, 
+        tenant: &Uuid
+    ) -> Result<Quota, sqlx::Error> {
+        sqlx::query!("INSERT INTO quotas (tenant) VALUES ($1) ON CONFLICT (tenant) DO NOTHING", tenant)
+            .execute(&mut **tx)
+            .await?;
+
+        let row = sqlx::query_as::<_, Quota>("SELECT * FROM quotas WHERE tenant = $1 FOR UPDATE")
+            .bind(tenant)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn get_quota(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        tenant: &Uuid
+    ) -> Result<Quota, sqlx::Error> {
+        let row = sqlx::query_as::<_, Quota>("SELECT * FROM quotas WHERE tenant = $1")
+            .bind(tenant)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row.unwrap_or_default())
+    }
+
+    pub async fn set_quota(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        tenant: &Uuid, 
+        quota: &Quota
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO quotas (tenant, vms, vcpus, ram, disk, volumes, vpcs, floating_ips, snapshots) 
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) 
+             ON CONFLICT (tenant) DO UPDATE SET 
+                vms = COALESCE($2, quotas.vms), vcpus = COALESCE($3, quotas.vcpus), ram = COALESCE($4, quotas.ram), 
+                disk = COALESCE($5, quotas.disk), volumes = COALESCE($6, quotas.volumes), vpcs = COALESCE($7, quotas.vpcs), 
+                floating_ips = COALESCE($8, quotas.floating_ips), snapshots = COALESCE($9, quotas.snapshots)", 
+            tenant, quota.vms, quota.vcpus, quota.ram, quota.disk, quota.volumes, quota.vpcs, quota.floating_ips, quota.snapshots)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    // Disk usage covers both VM root disks and volumes. Floating IPs do not
+    // exist yet, their quota is only stored.
+    pub async fn get_resource_usage(
+        executor: impl sqlx::PgExecutor<'_>, 
+        tenant: &Uuid
+    ) -> Result<ResourceUsage, sqlx::Error> {
+        let row = sqlx::query_as::<_, ResourceUsage>(
+            "SELECT 
+                (SELECT COUNT(*) FROM vms WHERE tenant = $1) AS vms,
+                (SELECT COALESCE(SUM(cpu), 0) FROM vms WHERE tenant = $1) AS vcpus,
+                (SELECT COALESCE(SUM(ram), 0) FROM vms WHERE tenant = $1) AS ram,
+                (SELECT COALESCE(SUM(disk_size), 0) FROM vms WHERE tenant = $1) 
+                    + (SELECT COALESCE(SUM(size), 0) FROM volumes WHERE tenant = $1) AS disk,
+                (SELECT COUNT(*) FROM volumes WHERE tenant = $1) AS volumes,
+                (SELECT COUNT(*) FROM vpcs WHERE tenant = $1) AS vpcs,
+                0::bigint AS floating_ips,
+                (SELECT COUNT(*) FROM snapshots WHERE tenant = $1) AS snapshots")
+            .bind(tenant)
+            .fetch_one(executor)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn create_server_group(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        tenant: &Uuid, 
+        policy: &str
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO server_groups (name, tenant, policy) VALUES ($1, $2, $3)", 
+            name, tenant, policy)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn get_server_group_by_name(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        name: &str, 
+        tenant: &Uuid
+    ) -> Result<Option<ServerGroup>, sqlx::Error> {
+        let row = sqlx::query_as::<_, ServerGroup>("SELECT * FROM server_groups WHERE name = $1 AND tenant = $2")
+            .bind(name)
+            .bind(tenant)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn get_server_group_by_id(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<Option<ServerGroup>, sqlx::Error> {
+        let row = sqlx::query_as::<_, ServerGroup>("SELECT * FROM server_groups WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        Ok(row)
+    }
+
+    pub async fn list_server_groups(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        tenant: &Uuid
+    ) -> Result<Vec<ServerGroup>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, ServerGroup>("SELECT * FROM server_groups WHERE tenant = $1")
+            .bind(tenant)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(rows)
+    }
+
+    // One entry per member VM, hypervisors hosting several members repeat.
+    pub async fn list_server_group_hypervisors(
+        executor: impl sqlx::PgExecutor<'_>,
+        server_group: &Uuid
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        let rows = sqlx::query!("SELECT hypervisor FROM vms WHERE server_group = $1", server_group)
+            .fetch_all(executor)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| r.hypervisor).collect())
+    }
+
+    // Serializes VM placements within the group until the transaction ends.
+    // The VM being placed is left out of the members returned, so are members
+    // on down hypervisors as they are about to be evacuated.
+    pub async fn lock_server_group(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        server_group: &Uuid,
+        vm: &str
+    ) -> Result<Vec<Uuid>, sqlx::Error> {
+        sqlx::query!("SELECT id FROM server_groups WHERE id = $1 FOR UPDATE", server_group)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        let rows = sqlx::query!(
+            "SELECT v.hypervisor FROM vms v JOIN hypervisors h ON h.id = v.hypervisor 
+             WHERE v.server_group = $1 AND v.name <> $2 AND h.status <> 'down'", 
+            server_group, vm)
+            .fetch_all(&mut **tx)
+            .await?;
+
+        Ok(rows.into_iter().map(|r| r.hypervisor).collect())
+    }
+
+    pub async fn set_vm_server_group(
+        executor: impl sqlx::PgExecutor<'_>,
+        name: &str, 
+        tenant: &Uuid, 
+        server_group: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vms SET server_group = $1 WHERE name = $2 AND tenant = $3", 
+            server_group, name, tenant)
+            .execute(executor)
+            .await?;
+    
+        Ok(())
+    }
+
+    pub async fn delete_server_group(
+        pool: &sqlx::Pool<sqlx::Postgres>, 
+        id: &Uuid
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM server_groups WHERE id = $1", id)
+            .execute(pool)
+            .await?;
+    
+        Ok(())
+    }
 }
\ No newline at end of file
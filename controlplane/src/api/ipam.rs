@@ -0,0 +1,70 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use sqlx::types::ipnetwork::{IpNetwork, Ipv4Network};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use crate::api::ProviderNetwork;
+
+
+pub fn parse_subnet(subnet: &str) -> Result<Ipv4Network, String> {
+    match subnet.parse::<IpNetwork>() {
+        Ok(IpNetwork::V4(subnet)) => Ok(subnet),
+        Ok(IpNetwork::V6(_)) => Err("Only IPv4 subnets are supported for provider networks.".to_string()),
+        Err(e) => Err(format!("Invalid subnet '{}': {}", subnet, e)),
+    }
+}
+
+pub fn subnet_contains(subnet: &Ipv4Network, ip: &IpNetwork) -> bool {
+    matches!(ip.ip(), IpAddr::V4(ip) if subnet.contains(ip))
+}
+
+pub fn allocation_range(
+    subnet: &Ipv4Network,
+    start: Option<IpNetwork>,
+    end: Option<IpNetwork>
+) -> Result<(u32, u32), String> {
+    // Network and broadcast addresses are excluded unless the subnet is too
+    // small to have them (/31 and /32).
+    let (mut first, mut last) = (u32::from(subnet.network()), u32::from(subnet.broadcast()));
+    if subnet.prefix() < 31 {
+        first += 1;
+        last -= 1;
+    }
+
+    for (bound, value) in [(&mut first, start), (&mut last, end)] {
+        match value {
+            Some(IpNetwork::V4(ip)) if subnet.contains(ip.ip()) => *bound = u32::from(ip.ip()),
+            Some(ip) => return Err(format!("Allocation pool address {} is outside of subnet {}.", ip.ip(), subnet)),
+            None => (),
+        }
+    }
+
+    if first > last {
+        return Err("Allocation pool start must come before its end.".to_string());
+    }
+
+    Ok((first, last))
+}
+
+pub fn allocate_address(network: &ProviderNetwork, used: &[IpNetwork]) -> Result<IpNetwork, String> {
+    let subnet = parse_subnet(&network.subnet)?;
+    let (first, last) = allocation_range(&subnet, network.allocation_start, network.allocation_end)?;
+
+    let reserved: HashSet<IpAddr> = used.iter()
+        .chain(network.gateway.iter())
+        .chain(network.dns_servers.iter())
+        .map(|ip| ip.ip())
+        .collect();
+
+    for addr in first..=last {
+        let ip = Ipv4Addr::from(addr);
+        if !reserved.contains(&IpAddr::V4(ip)) {
+            return Ipv4Network::new(ip, subnet.prefix())
+                .map(IpNetwork::V4)
+                .map_err(|e| e.to_string());
+        }
+    }
+
+    Err(format!("No free addresses left on provider network '{}'.", network.name))
+}
@@ -0,0 +1,69 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use crate::api::database::Database;
+
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+struct Config {
+    #[serde(default)]
+    liveness: LivenessConfig,
+}
+
+// Agents post their stats every 60 seconds, a hypervisor that missed two of
+// them is suspect and one that missed five is down.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct LivenessConfig {
+    #[serde(default = "default_check_interval")]
+    pub check_interval: u64,
+    #[serde(default = "default_suspect_after")]
+    suspect_after: i32,
+    #[serde(default = "default_down_after")]
+    down_after: i32,
+}
+
+impl Default for LivenessConfig {
+    fn default() -> Self {
+        LivenessConfig {
+            check_interval: default_check_interval(),
+            suspect_after: default_suspect_after(),
+            down_after: default_down_after(),
+        }
+    }
+}
+
+fn default_check_interval() -> u64 {
+    30
+}
+
+fn default_suspect_after() -> i32 {
+    150
+}
+
+fn default_down_after() -> i32 {
+    330
+}
+
+pub fn read_conf_file(config_file: &str) -> Result<LivenessConfig, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config.liveness)
+}
+
+// Moves hypervisors between up, suspect and down based on the last time their
+// agent was heard from. VMs on down hypervisors are marked unknown, the agent
+// reports their actual state once it is back.
+pub async fn check_hypervisors(config: &LivenessConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::new().await?;
+
+    for (hostname, status) in Database::update_hypervisors_status(&db, &config.suspect_after, &config.down_after).await? {
+        eprintln!("Hypervisor '{}' is now {}.", hostname, status);
+    }
+
+    let vms = Database::mark_down_hypervisors_vms(&db).await?;
+    if vms > 0 {
+        eprintln!("Marked {} VM(s) on down hypervisors as unknown.", vms);
+    }
+
+    Ok(())
+}
@@ -18,8 +18,11 @@ struct Config {
 struct OvnAPI {
     host: String,
     port: u16,
+    physnet: String,
 }
 
+const DNS_DOMAIN_SUFFIX: &str = "internal";
+
 fn read_conf_file(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
     let file = std::fs::read_to_string(config_file)?;
     let config: Config = serde_yaml::from_str(&file)?;
@@ -65,6 +68,11 @@ pub async fn delete_l2_switch(name: &str) -> Result<(), std::io::Error> {
                 "table": "Logical_Switch",
                 "where": [["name", "==", name]]
             },
+            {
+                "op": "delete",
+                "table": "DNS",
+                "where": [["external_ids", "includes", ["map", [["awp-switch", name]]]]]
+            },
             {
                 "op": "comment",
                 "comment": format!("Deleted by delete_l2_switch {} at {}", name, chrono::Utc::now())
@@ -77,6 +85,360 @@ pub async fn delete_l2_switch(name: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
+pub fn provider_switch_name(network: &str) -> String {
+    format!("provider-{}", network)
+}
+
+pub async fn create_provider_switch(network: &str, vlan: &i32, subnet: &str) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+    let switch_name = provider_switch_name(network);
+
+    // The localnet port bridges the logical switch to the physical network
+    // through ovn-bridge-mappings on each chassis, tagging traffic with the VLAN.
+    let request_body = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+                "op": "insert",
+                "table": "Logical_Switch_Port",
+                "row": {
+                    "name": format!("{}-localnet", &switch_name),
+                    "type": "localnet",
+                    "addresses": "unknown",
+                    "tag": vlan,
+                    "options": ["map", [["network_name", &conf_file.ovn.physnet]]]
+                },
+                "uuid-name": "localnet1"
+            },
+            {
+                "op": "insert",
+                "table": "Logical_Switch",
+                "row": {
+                    "name": &switch_name,
+                    "ports": ["set", [["named-uuid", "localnet1"]]],
+                    "other_config": ["map", [["subnet", subnet]]],
+                    "external_ids": ["map", [["awp-provider-network", network]]]
+                }
+            },
+            {
+                "op": "comment",
+                "comment": format!("Added by create_provider_switch {} vlan={} at {}", &switch_name, vlan, chrono::Utc::now())
+            }
+        ],
+        "id": 24
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&request_body, conf_file).await?;
+    Ok(())
+}
+
+pub async fn delete_provider_switch(network: &str) -> Result<(), std::io::Error> {
+    delete_l2_switch(&provider_switch_name(network)).await
+}
+
+pub async fn add_static_address_to_lsp(port_uuid: &str, mac_address: &str, ip_address: Option<&str>) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+    let addresses = match ip_address {
+        Some(ip_address) => format!("{} {}", mac_address, ip_address),
+        None => mac_address.to_string(),
+    };
+
+    let lsp_set_addresses_request = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+                "op": "update",
+                "table": "Logical_Switch_Port",
+                "row": {
+                    "addresses": &addresses,
+                    "port_security": &addresses
+                },
+                "where": [["_uuid", "==", ["uuid", &port_uuid]]]
+            },
+            {
+                "op": "comment",
+                "comment": format!("Static addresses defined by add_static_address_to_lsp for {}", &port_uuid)
+            }
+        ],
+        "id": 25
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&lsp_set_addresses_request, conf_file).await?;
+    Ok(())
+}
+
+pub async fn set_lsp_qos(
+    switch_name: &str,
+    port_name: &str,
+    ingress_kbps: Option<i32>,
+    egress_kbps: Option<i32>,
+    burst_kbit: Option<i32>
+) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+
+    let fetch_qos_uuids = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+            "op": "select",
+            "table": "QoS",
+            "where": [["external_ids", "includes", ["map", [["awp-port", port_name]]]]],
+            "columns": ["_uuid"]
+            }
+        ],
+        "id": 26
+    }).to_string() + "\n";
+
+    let qos_response = write_to_ovsdb(&fetch_qos_uuids, conf_file.clone()).await?;
+    let qos_response: serde_json::Value = serde_json::from_str(&qos_response)?;
+    let existing_rules: Vec<serde_json::Value> = qos_response["result"][0]["rows"].as_array()
+        .map(|rows| rows.iter().map(|row| row["_uuid"].clone()).collect())
+        .unwrap_or_default();
+
+    // QoS rows are only referenced by the switch, dropping the reference
+    // garbage collects the previous rules before the new ones are added.
+    let mut operations = vec![json!("OVN_Northbound")];
+    let mut new_rules = Vec::new();
+    if !existing_rules.is_empty() {
+        operations.push(json!({
+            "op": "mutate",
+            "table": "Logical_Switch",
+            "where": [["name", "==", switch_name]],
+            "mutations": [["qos_rules", "delete", ["set", existing_rules]]]
+        }));
+    }
+
+    // to-lport matches traffic towards the VM (ingress), from-lport traffic it sends (egress).
+    for (direction, rate, port_match) in [
+        ("to-lport", ingress_kbps, format!("outport == \"{}\"", port_name)),
+        ("from-lport", egress_kbps, format!("inport == \"{}\"", port_name)),
+    ] {
+        if let Some(rate) = rate {
+            let mut bandwidth = vec![json!(["rate", rate])];
+            if let Some(burst) = burst_kbit {
+                bandwidth.push(json!(["burst", burst]));
+            }
+
+            let uuid_name = format!("qos_{}", direction.replace('-', "_"));
+            operations.push(json!({
+                "op": "insert",
+                "table": "QoS",
+                "row": {
+                    "priority": 100,
+                    "direction": direction,
+                    "match": port_match,
+                    "action": ["map", []],
+                    "bandwidth": ["map", bandwidth],
+                    "external_ids": ["map", [["awp-port", port_name]]]
+                },
+                "uuid-name": &uuid_name
+            }));
+            new_rules.push(json!(["named-uuid", uuid_name]));
+        }
+    }
+
+    if !new_rules.is_empty() {
+        operations.push(json!({
+            "op": "mutate",
+            "table": "Logical_Switch",
+            "where": [["name", "==", switch_name]],
+            "mutations": [["qos_rules", "insert", ["set", new_rules]]]
+        }));
+    }
+
+    operations.push(json!({
+        "op": "comment",
+        "comment": format!("Updated by set_lsp_qos for {} at {}", port_name, chrono::Utc::now())
+    }));
+
+    let request_body = json!({
+        "method": "transact",
+        "params": operations,
+        "id": 27
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&request_body, conf_file).await?;
+    Ok(())
+}
+
+// Binds the port to the given chassis, named after their hypervisor hostname.
+// During a live migration the port is bound to both ends and only activated
+// on the target once the guest announces itself there with a RARP.
+// No chassis clears the options, the port then binds wherever the VM runs.
+pub async fn set_lsp_requested_chassis(port_name: &str, chassis: &[&str], activation_strategy: Option<&str>) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+
+    let mut options = vec![json!(["requested-chassis", chassis.join(",")])];
+    if let Some(activation_strategy) = activation_strategy {
+        options.push(json!(["activation-strategy", activation_strategy]));
+    }
+
+    let mut mutations = vec![json!(["options", "delete", ["set", ["requested-chassis", "activation-strategy"]]])];
+    if !chassis.is_empty() {
+        mutations.push(json!(["options", "insert", ["map", options]]));
+    }
+
+    let request_body = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+                "op": "mutate",
+                "table": "Logical_Switch_Port",
+                "where": [["name", "==", port_name]],
+                "mutations": mutations
+            },
+            {
+                "op": "comment",
+                "comment": format!("Updated by set_lsp_requested_chassis for {} at {}", port_name, chrono::Utc::now())
+            }
+        ],
+        "id": 28
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&request_body, conf_file).await?;
+    Ok(())
+}
+
+pub fn dns_domain(vpc: &str, tenant: &str) -> String {
+    format!("{}.{}.{}", vpc, tenant, DNS_DOMAIN_SUFFIX).to_lowercase()
+}
+
+pub async fn create_dns_table(switch_name: &str, domain: &str) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+
+    let request_body = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+                "op": "insert",
+                "table": "DNS",
+                "row": {
+                    "records": ["map", []],
+                    "external_ids": ["map", [["awp-switch", switch_name], ["awp-domain", domain]]]
+                },
+                "uuid-name": "dns1"
+            },
+            {
+                "op": "mutate",
+                "table": "Logical_Switch",
+                "where": [["name", "==", switch_name]],
+                "mutations": [
+                    ["dns_records", "insert", ["set", [["named-uuid", "dns1"]]]]
+                ]
+            },
+            {
+                "op": "comment",
+                "comment": format!("Added by create_dns_table {} for {} at {}", domain, switch_name, chrono::Utc::now())
+            }
+        ],
+        "id": 21
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&request_body, conf_file).await?;
+    Ok(())
+}
+
+pub async fn set_dns_record(switch_name: &str, hostname: &str, ip_addresses: &[String]) -> Result<(), std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+    let hostname = hostname.to_lowercase();
+
+    // Records are keyed by hostname, drop the previous entry first so that
+    // the insert mutation below replaces the IPs instead of being ignored.
+    let mut mutations = vec![json!(["records", "delete", ["set", [&hostname]]])];
+    if !ip_addresses.is_empty() {
+        mutations.push(json!(["records", "insert", ["map", [[&hostname, ip_addresses.join(" ")]]]]));
+    }
+
+    let request_body = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+                "op": "mutate",
+                "table": "DNS",
+                "where": [["external_ids", "includes", ["map", [["awp-switch", switch_name]]]]],
+                "mutations": mutations
+            },
+            {
+                "op": "comment",
+                "comment": format!("Updated by set_dns_record {}={:?} at {}", &hostname, ip_addresses, chrono::Utc::now())
+            }
+        ],
+        "id": 22
+    }).to_string() + "\n";
+
+    write_to_ovsdb(&request_body, conf_file).await?;
+    Ok(())
+}
+
+pub async fn remove_dns_record(switch_name: &str, hostname: &str) -> Result<(), std::io::Error> {
+    set_dns_record(switch_name, hostname, &[]).await
+}
+
+pub async fn get_lsp_dynamic_addresses(port_name: &str) -> Result<Vec<String>, std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+
+    let fetch_lsp = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+            "op": "select",
+            "table": "Logical_Switch_Port",
+            "where": [["name", "==", port_name]],
+            "columns": ["dynamic_addresses"]
+            }
+        ],
+        "id": 23
+    }).to_string() + "\n";
+
+    // dynamic_addresses is filled by northd as "<mac> <ip>", it is an empty
+    // set until the allocation happened.
+    let response = write_to_ovsdb(&fetch_lsp, conf_file).await?;
+    let json_response: serde_json::Value = serde_json::from_str(&response)?;
+    let ip_addresses = match json_response["result"][0]["rows"][0]["dynamic_addresses"].as_str() {
+        Some(addresses) => addresses.split_whitespace().skip(1).map(|ip| ip.to_string()).collect(),
+        None => Vec::new(),
+    };
+
+    Ok(ip_addresses)
+}
+
+// addresses holds "<mac> dynamic" or "<mac> <ip>", OVSDB returns a single
+// element set as a plain string.
+pub async fn get_lsp_mac_address(port_name: &str) -> Result<Option<String>, std::io::Error> {
+    let conf_file: Config = read_conf_file("config.yaml").unwrap();
+
+    let fetch_lsp = json!({
+        "method": "transact",
+        "params": [
+            "OVN_Northbound",
+            {
+            "op": "select",
+            "table": "Logical_Switch_Port",
+            "where": [["name", "==", port_name]],
+            "columns": ["addresses"]
+            }
+        ],
+        "id": 29
+    }).to_string() + "\n";
+
+    let response = write_to_ovsdb(&fetch_lsp, conf_file).await?;
+    let json_response: serde_json::Value = serde_json::from_str(&response)?;
+    let addresses = &json_response["result"][0]["rows"][0]["addresses"];
+    let address = match addresses.as_str() {
+        Some(address) => Some(address),
+        None => addresses[1][0].as_str(),
+    };
+
+    Ok(address.and_then(|address| address.split_whitespace().next()).map(|mac| mac.to_string()))
+}
+
 pub async fn add_lsp_to_ls(port_name: &str, switch_name: &str) -> Result<String, std::io::Error> {
     let conf_file: Config = read_conf_file("config.yaml").unwrap();
 
@@ -225,7 +587,7 @@ pub async fn delete_dhcpv4_options(cidr: &str) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-pub async fn create_dhcpv4_options(cidr: &str) -> Result<String, std::io::Error> {
+pub async fn create_dhcpv4_options(cidr: &str, domain: &str) -> Result<String, std::io::Error> {
     let conf_file: Config = read_conf_file("config.yaml").unwrap();
     let mac_addr = generate_mac_address().await;
     let mac_addr_as_string = format!(
@@ -250,7 +612,8 @@ pub async fn create_dhcpv4_options(cidr: &str) -> Result<String, std::io::Error>
                             ["lease_time", "3600"],
                             ["router", &router_ip],
                             ["server_id", &router_ip],
-                            ["server_mac", &mac_addr_as_string]
+                            ["server_mac", &mac_addr_as_string],
+                            ["domain_name", format!("\"{}\"", domain)]
                         ]
                     ]
                 },
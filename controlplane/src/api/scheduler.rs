@@ -0,0 +1,276 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use crate::api::HypervisorScheduler;
+use sqlx::types::Uuid;
+
+
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+struct Config {
+    #[serde(default)]
+    scheduler: SchedulerConfig,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SchedulerConfig {
+    #[serde(default = "default_filters")]
+    filters: Vec<Filter>,
+    #[serde(default = "default_weighers")]
+    weighers: Vec<WeigherConfig>,
+    #[serde(default)]
+    overcommit: Overcommit,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+}
+
+// Global defaults, hypervisors can override each value on their own.
+#[derive(serde::Deserialize, Debug, Clone)]
+struct Overcommit {
+    #[serde(default = "default_allocation_ratio")]
+    cpu_allocation_ratio: f64,
+    #[serde(default = "default_allocation_ratio")]
+    ram_allocation_ratio: f64,
+    #[serde(default)]
+    reserved_cpu: i32,
+    #[serde(default)]
+    reserved_ram: i32,
+}
+
+// What a hypervisor can hand out to VMs once its own reservation is taken
+// out and the allocation ratios are applied.
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct Capacity {
+    pub cpu_allocation_ratio: f64,
+    pub ram_allocation_ratio: f64,
+    pub reserved_cpu: i32,
+    pub reserved_ram: i32,
+    pub cpu: i32,
+    pub ram: i32,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct WeigherConfig {
+    name: Weigher,
+    #[serde(default = "default_multiplier")]
+    multiplier: f64,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    Arch,
+    Capacity,
+    Image,
+    Maintenance,
+    Liveness,
+    ServerGroup,
+    AvailabilityZone,
+    Labels,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Weigher {
+    ImageCached,
+    LeastVms,
+    MostFreeRam,
+    BinPacking,
+    Spread,
+    ServerGroup,
+}
+
+// What the VM being placed needs from a hypervisor.
+pub struct RequestSpec<'a> {
+    pub cpu: i32,
+    pub ram: i32,
+    pub arch: &'a str,
+    pub image: &'a str,
+    pub group_policy: Option<&'a str>,
+    pub group_hypervisors: &'a [Uuid],
+    pub availability_zone: Option<&'a str>,
+    pub label_selector: &'a [String],
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            filters: default_filters(),
+            weighers: default_weighers(),
+            overcommit: Overcommit::default(),
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
+impl Default for Overcommit {
+    fn default() -> Self {
+        Overcommit {
+            cpu_allocation_ratio: default_allocation_ratio(),
+            ram_allocation_ratio: default_allocation_ratio(),
+            reserved_cpu: 0,
+            reserved_ram: 0,
+        }
+    }
+}
+
+// Matches the placement done before filters and weighers were configurable,
+// hypervisors caching the image first and then the ones hosting the fewest VMs.
+fn default_filters() -> Vec<Filter> {
+    vec![Filter::Arch, Filter::Liveness, Filter::Maintenance, Filter::Capacity, Filter::ServerGroup, Filter::AvailabilityZone, Filter::Labels]
+}
+
+fn default_weighers() -> Vec<WeigherConfig> {
+    vec![
+        WeigherConfig { name: Weigher::ServerGroup, multiplier: 100.0 },
+        WeigherConfig { name: Weigher::ImageCached, multiplier: 10.0 },
+        WeigherConfig { name: Weigher::LeastVms, multiplier: 1.0 },
+    ]
+}
+
+fn default_multiplier() -> f64 {
+    1.0
+}
+
+fn default_allocation_ratio() -> f64 {
+    1.0
+}
+
+fn default_max_attempts() -> usize {
+    3
+}
+
+pub fn read_conf_file(config_file: &str) -> Result<SchedulerConfig, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config.scheduler)
+}
+
+pub fn capacity(config: &SchedulerConfig, hypervisor: &HypervisorScheduler) -> Capacity {
+    let cpu_allocation_ratio = hypervisor.cpu_allocation_ratio.unwrap_or(config.overcommit.cpu_allocation_ratio);
+    let ram_allocation_ratio = hypervisor.ram_allocation_ratio.unwrap_or(config.overcommit.ram_allocation_ratio);
+    let reserved_cpu = hypervisor.reserved_cpu.unwrap_or(config.overcommit.reserved_cpu);
+    let reserved_ram = hypervisor.reserved_ram.unwrap_or(config.overcommit.reserved_ram);
+
+    Capacity {
+        cpu_allocation_ratio,
+        ram_allocation_ratio,
+        reserved_cpu,
+        reserved_ram,
+        cpu: (f64::from((hypervisor.total_cpu - reserved_cpu).max(0)) * cpu_allocation_ratio).floor() as i32,
+        ram: (f64::from((hypervisor.total_ram - reserved_ram).max(0)) * ram_allocation_ratio).floor() as i32,
+    }
+}
+
+impl Capacity {
+    // Pending claims count as used until the agent reports their VMs.
+    pub fn fits(&self, hypervisor: &HypervisorScheduler, cpu: i32, ram: i32) -> bool {
+        self.free_ram(hypervisor) >= ram && self.cpu - hypervisor.used_cpu - hypervisor.claimed_cpu >= cpu
+    }
+
+    fn free_ram(&self, hypervisor: &HypervisorScheduler) -> i32 {
+        self.ram - hypervisor.used_ram - hypervisor.claimed_ram
+    }
+
+    fn usage(&self, hypervisor: &HypervisorScheduler) -> f64 {
+        let ram_usage = f64::from(hypervisor.used_ram + hypervisor.claimed_ram) / f64::from(self.ram.max(1));
+        let cpu_usage = f64::from(hypervisor.used_cpu + hypervisor.claimed_cpu) / f64::from(self.cpu.max(1));
+        ram_usage.max(cpu_usage)
+    }
+}
+
+pub fn is_hard_policy(policy: &str) -> bool {
+    policy == "affinity" || policy == "anti-affinity"
+}
+
+// Soft policies never rule a hypervisor out, they are left to the weigher.
+// The first member of an affinity group can go anywhere.
+pub fn policy_allows(policy: &str, group_hypervisors: &[Uuid], hypervisor: &Uuid) -> bool {
+    match policy {
+        "affinity" => group_hypervisors.is_empty() || group_hypervisors.contains(hypervisor),
+        "anti-affinity" => !group_hypervisors.contains(hypervisor),
+        _ => true,
+    }
+}
+
+pub fn in_zone(hypervisor: &HypervisorScheduler, availability_zone: Option<&str>) -> bool {
+    availability_zone.is_none_or(|zone| hypervisor.availability_zone.as_deref() == Some(zone))
+}
+
+// Every label of the selector must be carried by the hypervisor.
+pub fn has_labels(hypervisor: &HypervisorScheduler, label_selector: &[String]) -> bool {
+    label_selector.iter().all(|label| hypervisor.labels.contains(label))
+}
+
+impl Filter {
+    fn passes(&self, hypervisor: &HypervisorScheduler, capacity: &Capacity, spec: &RequestSpec) -> bool {
+        match self {
+            Filter::Arch => hypervisor.arch == spec.arch,
+            Filter::Capacity => capacity.fits(hypervisor, spec.cpu, spec.ram),
+            Filter::Image => hypervisor.cached_images.iter().any(|image| image == spec.image),
+            Filter::Maintenance => !hypervisor.maintenance,
+            Filter::Liveness => hypervisor.status == "up",
+            Filter::ServerGroup => {
+                spec.group_policy.is_none_or(|policy| policy_allows(policy, spec.group_hypervisors, &hypervisor.id))
+            },
+            Filter::AvailabilityZone => in_zone(hypervisor, spec.availability_zone),
+            Filter::Labels => has_labels(hypervisor, spec.label_selector),
+        }
+    }
+}
+
+impl Weigher {
+    // Higher is better, values are normalized across the candidates before
+    // the multipliers are applied so that weighers can be freely combined.
+    fn weigh(&self, hypervisor: &HypervisorScheduler, capacity: &Capacity, spec: &RequestSpec) -> f64 {
+        match self {
+            Weigher::ImageCached => {
+                if hypervisor.cached_images.iter().any(|image| image == spec.image) { 1.0 } else { 0.0 }
+            },
+            Weigher::LeastVms => -f64::from(hypervisor.hosted_vms),
+            Weigher::MostFreeRam => f64::from(capacity.free_ram(hypervisor)),
+            Weigher::BinPacking => capacity.usage(hypervisor),
+            Weigher::Spread => -capacity.usage(hypervisor),
+            Weigher::ServerGroup => {
+                let members = spec.group_hypervisors.iter().filter(|id| **id == hypervisor.id).count() as f64;
+                match spec.group_policy {
+                    Some("soft-affinity") => members,
+                    Some("soft-anti-affinity") => -members,
+                    _ => 0.0,
+                }
+            },
+        }
+    }
+}
+
+// Returns the hypervisors that can host the VM, best candidate first. Ties
+// keep the order the hypervisors were passed in.
+pub fn schedule(
+    config: &SchedulerConfig,
+    hypervisors: Vec<HypervisorScheduler>,
+    spec: &RequestSpec
+) -> Vec<HypervisorScheduler> {
+    let candidates: Vec<(HypervisorScheduler, Capacity)> = hypervisors.into_iter()
+        .map(|hypervisor| {
+            let capacity = capacity(config, &hypervisor);
+            (hypervisor, capacity)
+        })
+        .filter(|(hypervisor, capacity)| config.filters.iter().all(|filter| filter.passes(hypervisor, capacity, spec)))
+        .collect();
+
+    let mut scores = vec![0.0; candidates.len()];
+    for weigher in config.weighers.iter() {
+        let weights: Vec<f64> = candidates.iter().map(|(hypervisor, capacity)| weigher.name.weigh(hypervisor, capacity, spec)).collect();
+        let min = weights.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = weights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+        if max > min {
+            for (score, weight) in scores.iter_mut().zip(weights) {
+                *score += weigher.multiplier * (weight - min) / (max - min);
+            }
+        }
+    }
+
+    let mut ranked: Vec<(f64, (HypervisorScheduler, Capacity))> = scores.into_iter().zip(candidates).collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranked.into_iter().map(|(_, (hypervisor, _))| hypervisor).collect()
+}
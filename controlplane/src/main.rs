@@ -8,6 +8,8 @@ use api::ControlPlaneAPI;
 
 #[tokio::main]
 async fn main() {
+    tokio::spawn(ControlPlaneAPI::monitor_hypervisors());
+
     let app = ControlPlaneAPI::router().await;
     let server = ControlPlaneAPI::start_server(app).await;
     match server {
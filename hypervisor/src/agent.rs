@@ -10,6 +10,7 @@ use std::net::IpAddr;
 use std::str::FromStr;
 use serde::{Serialize, Deserialize};
 use serde_json;
+use crate::api::HypervisorApi;
 
 #[derive(Serialize, Deserialize)]
 pub struct Hypervisor {
@@ -17,7 +18,29 @@ pub struct Hypervisor {
     memory: u64,
     cpu: usize,
     arch: String,
+    cpu_model: String,
     vms: Vec<VirtualMachine>,
+    images: Vec<String>,
+    availability_zone: Option<String>,
+    labels: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    placement: Option<PlacementConfig>,
+}
+
+// Left out zone and labels are managed through the controlplane API instead.
+#[derive(Deserialize, Debug)]
+struct PlacementConfig {
+    availability_zone: Option<String>,
+    labels: Option<Vec<String>>,
+}
+
+fn read_conf_file(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -40,6 +63,8 @@ impl Hypervisor {
         let memory = system.total_memory() / 1024 / 1024 / 1024;
         let cpu = system.cpus().len();
         let arch: String = std::env::consts::ARCH.to_string();
+        // Domains use host-passthrough, live migrations need the same model on both ends.
+        let cpu_model = system.cpus().first().map(|cpu| cpu.brand().to_string()).unwrap_or_default();
         let mut vms = Vec::new();
 
         let conn = Connect::open(Some("qemu:///system")).map_err(|e| format!("Failed to connect to libvirt: {}", e))?;
@@ -74,7 +99,14 @@ impl Hypervisor {
             });
         }
 
-        Ok(Hypervisor { hostname, memory, cpu, arch, vms })
+        let images = HypervisorApi::cached_images();
+        let placement = read_conf_file("config.yaml").ok().and_then(|config| config.placement);
+        let (availability_zone, labels) = match placement {
+            Some(placement) => (placement.availability_zone, placement.labels),
+            None => (None, None),
+        };
+
+        Ok(Hypervisor { hostname, memory, cpu, arch, cpu_model, vms, images, availability_zone, labels })
     }
 
     pub fn to_json(&self) -> Result<String, String> {
@@ -3,12 +3,22 @@
 
 mod libvirt;
 mod ovs;
+mod volume;
+mod snapshot;
+mod image;
+mod migration;
 
-use crate::api::libvirt::VmDomain;
+use crate::api::libvirt::{VmDomain, NetworkConfig, Bandwidth, ExtraSpecs};
+use crate::api::volume::VmVolume;
+use crate::api::snapshot::VmSnapshot;
+use crate::api::image::{BaseImage, ImageSpec};
+use crate::api::migration::VmMigration;
 
 use axum::{
-    extract::Json, http::StatusCode, response::IntoResponse, routing::post, Router
+    extract::Json, http::StatusCode, response::IntoResponse, routing::{get,post}, Router
 };
+use axum::{body::Body, extract::Path, http::HeaderMap};
+use tokio_util::io::ReaderStream;
 use tower_http::trace::{TraceLayer, DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse};
 
 
@@ -23,7 +33,16 @@ struct VirtualMachine {
     tenant: String,
     mac_addr: String,
     networking: String,
-    network: Option<String>,
+    fqdn: Option<String>,
+    network_config: Option<NetworkConfig>,
+    bandwidth: Option<Bandwidth>,
+    #[serde(default)]
+    copy_on_write: bool,
+    image: ImageSpec,
+    #[serde(default)]
+    extra_specs: ExtraSpecs,
+    #[serde(default)]
+    reuse_disk: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -32,6 +51,123 @@ struct VirtualMachineDelete {
     tenant: String,
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VirtualMachineImport {
+    name: String,
+    tenant: String,
+    source: String,
+    image: Option<ImageSpec>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VirtualMachineMigrate {
+    name: String,
+    tenant: String,
+    target: String,
+    image: Option<ImageSpec>,
+    bandwidth: Option<u64>,
+    #[serde(default)]
+    auto_converge: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MigrationPrepare {
+    name: String,
+    tenant: String,
+    source: String,
+    disk_size: u64,
+    image: Option<ImageSpec>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MigrationRequest {
+    name: String,
+    tenant: String,
+    #[serde(default)]
+    shared: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VirtualMachineBandwidth {
+    name: String,
+    tenant: String,
+    bandwidth: Bandwidth,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VirtualMachineResize {
+    name: String,
+    tenant: String,
+    cpu: u32,
+    memory: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VirtualMachineDiskResize {
+    name: String,
+    tenant: String,
+    size: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BaseImageCapture {
+    vm: String,
+    tenant: String,
+    file: String,
+    sysprep: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BaseImageDelete {
+    file: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VolumeCreate {
+    name: String,
+    tenant: String,
+    size: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VolumeDelete {
+    name: String,
+    tenant: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VolumeResize {
+    name: String,
+    tenant: String,
+    vm: Option<String>,
+    size: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct VolumeAttachment {
+    name: String,
+    tenant: String,
+    vm: String,
+    target_dev: String,
+    serial: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotCreate {
+    name: String,
+    tenant: String,
+    vm: String,
+    kind: String,
+    quiesce: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotRequest {
+    name: String,
+    tenant: String,
+    vm: String,
+}
+
 pub struct HypervisorApi {}
 
 impl HypervisorApi {
@@ -43,6 +179,33 @@ impl HypervisorApi {
         Router::new()
             .route("/virtualmachine/create", post(create_vm_handler))
             .route("/virtualmachine/delete", post(delete_vm_handler))
+            .route("/virtualmachine/stop", post(stop_vm_handler))
+            .route("/virtualmachine/undefine", post(undefine_vm_handler))
+            .route("/virtualmachine/import", post(import_vm_handler))
+            .route("/virtualmachine/migrate", post(migrate_vm_handler))
+            .route("/virtualmachine/migrate/progress", post(migration_progress_handler))
+            .route("/virtualmachine/bandwidth", post(bandwidth_vm_handler))
+            .route("/virtualmachine/flatten", post(flatten_vm_handler))
+            .route("/virtualmachine/resize", post(resize_vm_handler))
+            .route("/virtualmachine/resize_disk", post(resize_vm_disk_handler))
+            .route("/image/delete", post(delete_image_handler))
+            .route("/image/capture", post(capture_image_handler))
+            .route("/image/download/:file", get(download_image_handler))
+            .route("/volume/create", post(create_volume_handler))
+            .route("/volume/delete", post(delete_volume_handler))
+            .route("/volume/resize", post(resize_volume_handler))
+            .route("/volume/attach", post(attach_volume_handler))
+            .route("/volume/detach", post(detach_volume_handler))
+            .route("/snapshot/create", post(create_snapshot_handler))
+            .route("/snapshot/revert", post(revert_snapshot_handler))
+            .route("/snapshot/delete", post(delete_snapshot_handler))
+            .route("/migration/export/:tenant/:name", get(export_vm_handler))
+            .route("/migration/export/:tenant/:name/:file", get(export_vm_file_handler))
+            .route("/migration/prepare", post(prepare_migration_handler))
+            .route("/migration/abort", post(abort_migration_handler))
+            .route("/migration/finish", post(finish_migration_handler))
+            .route("/ovs/ports/list", get(list_ovs_ports_handler))
+            .route("/ovs/ports/gc", post(gc_ovs_ports_handler))
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(DefaultMakeSpan::new().include_headers(true))
@@ -51,6 +214,20 @@ impl HypervisorApi {
             )
     }
 
+    pub async fn setup_bridge_mappings() -> Result<(), std::io::Error> {
+        ovs::OvsDbRequest::set_bridge_mappings().await
+    }
+
+    pub async fn garbage_collect_ports() -> Result<Vec<String>, String> {
+        let domains = VmDomain::list_domain_names().map_err(|e| format!("Failed to list domains: {}", e))?;
+        ovs::OvsDbRequest::garbage_collect_ports(&domains).await
+            .map_err(|e| format!("Failed to garbage collect OVS ports: {}", e))
+    }
+
+    pub fn cached_images() -> Vec<String> {
+        BaseImage::cached()
+    }
+
     pub async fn start_server(app: Router) -> Result<(), Box<dyn std::error::Error>> {
         let listener = match tokio::net::TcpListener::bind("0.0.0.0:3000").await {
             Ok(listener) => {
@@ -75,12 +252,7 @@ impl HypervisorApi {
 async fn create_vm_handler(Json(payload): Json<VirtualMachine>) -> impl IntoResponse {
     let vm = serde_json::to_string(&payload).unwrap();
 
-    let os = match payload.os.as_str() {
-        "rhel9" | "fedora41" => payload.os,
-        _ => return (StatusCode::BAD_REQUEST, "Invalid OS specified. Only 'rhel9' and 'fedora41' are supported.".to_string()),
-    };
-
-    let create_vm = VmDomain::create_vm(payload.name, payload.memory, payload.cpu, os, payload.ssh_pub_key, payload.disk, payload.tenant, payload.mac_addr, payload.networking, payload.network).await;
+    let create_vm = VmDomain::create_vm(payload.name, payload.memory, payload.cpu, payload.image, payload.ssh_pub_key, payload.disk, payload.tenant, payload.mac_addr, payload.networking, payload.fqdn, payload.network_config, payload.bandwidth, payload.copy_on_write, payload.extra_specs, payload.reuse_disk).await;
     match create_vm {
         Ok(_) => (StatusCode::OK, format!("VM creation started successfully with specs: {}", vm)),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create VM: {}", e)),
@@ -95,4 +267,263 @@ async fn delete_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl In
         Ok(_) => (StatusCode::OK, format!("VM with name '{}' deleted successfully.", vm)),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete VM: {}", e)),
     }
-}
\ No newline at end of file
+}
+
+async fn stop_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    match VmDomain::stop_vm(payload.name.clone(), payload.tenant).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' stopped successfully.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to stop VM: {}", e)),
+    }
+}
+
+async fn undefine_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    match VmDomain::undefine_vm(payload.name.clone(), payload.tenant).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' undefined successfully, its disk was kept.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to undefine VM: {}", e)),
+    }
+}
+
+// Cold migration is pulled by the target hypervisor, the controlplane tells it
+// which hypervisor to take the VM from.
+async fn import_vm_handler(Json(payload): Json<VirtualMachineImport>) -> impl IntoResponse {
+    match VmMigration::import(payload.name.clone(), payload.tenant, payload.source.clone(), payload.image).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' imported from '{}'.", payload.name, payload.source)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::AlreadyExists => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to import VM: {}", e)),
+        },
+    }
+}
+
+fn agent_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get("X-AWP-Agent-Token").and_then(|token| token.to_str().ok())
+}
+
+async fn export_vm_handler(headers: HeaderMap, Path((tenant, name)): Path<(String, String)>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string());
+    }
+
+    match VmMigration::export(&name, &tenant) {
+        Ok(export) => (StatusCode::OK, serde_json::to_string(&export).unwrap()),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::ResourceBusy => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to export VM: {}", e)),
+        },
+    }
+}
+
+async fn export_vm_file_handler(headers: HeaderMap, Path((tenant, name, file)): Path<(String, String, String)>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string()).into_response();
+    }
+
+    match VmMigration::open_file(&name, &tenant, &file).await {
+        Ok(vm_file) => (StatusCode::OK, Body::from_stream(ReaderStream::new(vm_file))).into_response(),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::ResourceBusy => (StatusCode::CONFLICT, e.to_string()).into_response(),
+            _ => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        },
+    }
+}
+
+async fn migrate_vm_handler(Json(payload): Json<VirtualMachineMigrate>) -> impl IntoResponse {
+    match VmMigration::live_migrate(payload.name.clone(), payload.tenant, payload.target.clone(), payload.image, payload.bandwidth, payload.auto_converge).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' migrated to '{}'.", payload.name, payload.target)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to migrate VM: {}", e)),
+        },
+    }
+}
+
+async fn migration_progress_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    match VmMigration::progress(&payload.name, &payload.tenant) {
+        Ok(progress) => (StatusCode::OK, progress.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to get migration progress: {}", e)),
+    }
+}
+
+async fn prepare_migration_handler(headers: HeaderMap, Json(payload): Json<MigrationPrepare>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string());
+    }
+
+    match VmMigration::prepare(payload.name, payload.tenant, payload.source, payload.disk_size, payload.image).await {
+        Ok(true) => (StatusCode::OK, "shared".to_string()),
+        Ok(false) => (StatusCode::OK, "copy".to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to prepare migration: {}", e)),
+    }
+}
+
+async fn abort_migration_handler(headers: HeaderMap, Json(payload): Json<MigrationRequest>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string());
+    }
+
+    match VmMigration::abort(payload.name.clone(), payload.tenant, payload.shared).await {
+        Ok(_) => (StatusCode::OK, format!("Migration of VM '{}' aborted.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to abort migration: {}", e)),
+    }
+}
+
+async fn finish_migration_handler(headers: HeaderMap, Json(payload): Json<MigrationRequest>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string());
+    }
+
+    match VmMigration::finish(payload.name.clone(), payload.tenant).await {
+        Ok(_) => (StatusCode::OK, format!("Migration of VM '{}' finished.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to finish migration: {}", e)),
+    }
+}
+
+async fn bandwidth_vm_handler(Json(payload): Json<VirtualMachineBandwidth>) -> impl IntoResponse {
+    let set_bandwidth = VmDomain::set_bandwidth(payload.name.clone(), payload.tenant, payload.bandwidth).await;
+    match set_bandwidth {
+        Ok(_) => (StatusCode::OK, format!("Bandwidth of VM '{}' updated successfully.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to update bandwidth: {}", e)),
+    }
+}
+
+async fn flatten_vm_handler(Json(payload): Json<VirtualMachineDelete>) -> impl IntoResponse {
+    match VmDomain::flatten_disk(payload.name.clone(), payload.tenant).await {
+        Ok(_) => (StatusCode::OK, format!("Disk of VM '{}' flattened successfully.", payload.name)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::ResourceBusy => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to flatten disk: {}", e)),
+        },
+    }
+}
+
+async fn resize_vm_handler(Json(payload): Json<VirtualMachineResize>) -> impl IntoResponse {
+    match VmDomain::resize_vm(payload.name.clone(), payload.tenant, payload.cpu, payload.memory).await {
+        Ok(true) => (StatusCode::OK, "live".to_string()),
+        Ok(false) => (StatusCode::OK, "next_boot".to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize VM: {}", e)),
+    }
+}
+
+async fn resize_vm_disk_handler(Json(payload): Json<VirtualMachineDiskResize>) -> impl IntoResponse {
+    match VmDomain::resize_vm_disk(payload.name.clone(), payload.tenant, payload.size).await {
+        Ok(_) => (StatusCode::OK, format!("Disk of VM '{}' resized to {}G.", payload.name, payload.size)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize disk: {}", e)),
+        },
+    }
+}
+
+async fn delete_image_handler(Json(payload): Json<BaseImageDelete>) -> impl IntoResponse {
+    match BaseImage::delete(payload.file.clone()).await {
+        Ok(_) => (StatusCode::OK, format!("Base image '{}' deleted successfully.", payload.file)),
+        Err(e) if e.kind() == std::io::ErrorKind::ResourceBusy => (StatusCode::CONFLICT, e.to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete base image: {}", e)),
+    }
+}
+
+async fn capture_image_handler(Json(payload): Json<BaseImageCapture>) -> impl IntoResponse {
+    match BaseImage::capture(payload.vm, payload.tenant, payload.file, payload.sysprep).await {
+        Ok(checksum) => (StatusCode::OK, checksum),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::ResourceBusy => (StatusCode::CONFLICT, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to capture image: {}", e)),
+        },
+    }
+}
+
+async fn download_image_handler(headers: HeaderMap, Path(file): Path<String>) -> impl IntoResponse {
+    if let Err(e) = VmMigration::authorize(agent_token(&headers)) {
+        return (StatusCode::FORBIDDEN, e.to_string()).into_response();
+    }
+
+    match BaseImage::open(&file).await {
+        Ok(image_file) => (StatusCode::OK, Body::from_stream(ReaderStream::new(image_file))).into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+    }
+}
+
+async fn list_ovs_ports_handler() -> impl IntoResponse {
+    match ovs::OvsDbRequest::list_awp_ports().await {
+        Ok(ports) => (StatusCode::OK, serde_json::to_string(&ports).unwrap()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to list OVS ports: {}", e)),
+    }
+}
+
+async fn gc_ovs_ports_handler() -> impl IntoResponse {
+    match HypervisorApi::garbage_collect_ports().await {
+        Ok(removed) => (StatusCode::OK, serde_json::to_string(&removed).unwrap()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+    }
+}
+
+async fn create_volume_handler(Json(payload): Json<VolumeCreate>) -> impl IntoResponse {
+    match VmVolume::create_volume(payload.name.clone(), payload.tenant, payload.size).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' created successfully.", payload.name)),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create volume: {}", e)),
+    }
+}
+
+async fn delete_volume_handler(Json(payload): Json<VolumeDelete>) -> impl IntoResponse {
+    match VmVolume::delete_volume(payload.name.clone(), payload.tenant).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' deleted successfully.", payload.name)),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete volume: {}", e)),
+    }
+}
+
+async fn resize_volume_handler(Json(payload): Json<VolumeResize>) -> impl IntoResponse {
+    match VmVolume::resize_volume(payload.name.clone(), payload.tenant, payload.vm, payload.size).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' resized to {}G.", payload.name, payload.size)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to resize volume: {}", e)),
+        },
+    }
+}
+
+async fn attach_volume_handler(Json(payload): Json<VolumeAttachment>) -> impl IntoResponse {
+    match VmVolume::attach_volume(payload.name.clone(), payload.tenant, payload.vm.clone(), payload.target_dev, payload.serial).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' attached to VM '{}'.", payload.name, payload.vm)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to attach volume: {}", e)),
+        },
+    }
+}
+
+async fn detach_volume_handler(Json(payload): Json<VolumeAttachment>) -> impl IntoResponse {
+    match VmVolume::detach_volume(payload.name.clone(), payload.tenant, payload.vm.clone(), payload.target_dev, payload.serial).await {
+        Ok(_) => (StatusCode::OK, format!("Volume '{}' detached from VM '{}'.", payload.name, payload.vm)),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to detach volume: {}", e)),
+        },
+    }
+}
+
+async fn create_snapshot_handler(Json(payload): Json<SnapshotCreate>) -> impl IntoResponse {
+    match VmSnapshot::create_snapshot(payload.name, payload.tenant, payload.vm, payload.kind, payload.quiesce).await {
+        Ok(disks) => (StatusCode::OK, serde_json::to_string(&disks).unwrap()),
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) if io_error.kind() == std::io::ErrorKind::InvalidInput => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to create snapshot: {}", e)),
+        },
+    }
+}
+
+async fn revert_snapshot_handler(Json(payload): Json<SnapshotRequest>) -> impl IntoResponse {
+    match VmSnapshot::revert_snapshot(payload.name.clone(), payload.tenant, payload.vm.clone()).await {
+        Ok(_) => (StatusCode::OK, format!("VM '{}' reverted to snapshot '{}'.", payload.vm, payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to revert snapshot: {}", e)),
+    }
+}
+
+async fn delete_snapshot_handler(Json(payload): Json<SnapshotRequest>) -> impl IntoResponse {
+    match VmSnapshot::delete_snapshot(payload.name.clone(), payload.tenant, payload.vm).await {
+        Ok(_) => (StatusCode::OK, format!("Snapshot '{}' deleted successfully.", payload.name)),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to delete snapshot: {}", e)),
+    }
+}
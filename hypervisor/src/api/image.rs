@@ -0,0 +1,464 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use std::io;
+use std::fs;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::error::Error;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use virt::connect::Connect;
+use virt::domain::Domain;
+use crate::api::migration::VmMigration;
+
+
+static LIBVIRT_STORAGE_PATH: &str = "/var/lib/libvirt/images";
+static IMAGE_CACHE_INDEX: &str = "/var/lib/libvirt/images/.awp-image-cache.json";
+static DEFAULT_CACHE_SIZE_GB: u64 = 100;
+
+// One lock per image file serializes its fetch, capture, eviction and
+// deletion, concurrent creates of VMs from the same image must not download
+// it twice into the same file while other images are left alone.
+static IMAGE_LOCKS: std::sync::Mutex<BTreeMap<String, Arc<Mutex<()>>>> = std::sync::Mutex::new(BTreeMap::new());
+
+// Only held while the cache index is read and written back.
+static IMAGE_INDEX_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    images: Option<ImageStoreConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ImageStoreConfig {
+    store: String,
+    cache_size_gb: Option<u64>,
+}
+
+fn read_conf_file(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config)
+}
+
+// Catalog entry of the image a VM is created from, as sent by the controlplane.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageSpec {
+    pub file: String,
+    pub format: String,
+    pub default_user: String,
+    pub cloud_init: bool,
+    pub checksum: String,
+}
+
+enum ImageDigest {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl ImageDigest {
+    fn new(checksum: &str) -> Result<(Self, String), io::Error> {
+        match checksum.split_once(':') {
+            Some(("sha256", digest)) => Ok((ImageDigest::Sha256(Sha256::new()), digest.to_lowercase())),
+            Some(("sha512", digest)) => Ok((ImageDigest::Sha512(Sha512::new()), digest.to_lowercase())),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported image checksum: {}", checksum))),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ImageDigest::Sha256(hasher) => hasher.update(data),
+            ImageDigest::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            ImageDigest::Sha256(hasher) => hex::encode(hasher.finalize()),
+            ImageDigest::Sha512(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+}
+
+pub struct BaseImage {}
+
+impl BaseImage {
+    pub fn path(file: &str) -> String {
+        format!("{}/{}", LIBVIRT_STORAGE_PATH, file)
+    }
+
+    // Image files come from API requests, they must stay inside the cache.
+    fn check_file(file: &str) -> Result<(), io::Error> {
+        if file.is_empty() || file.starts_with('.') || file.contains('/') || file.contains("..") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid image file name: {}", file)));
+        }
+
+        Ok(())
+    }
+
+    pub fn backing_file(disk: &str) -> Result<Option<String>, io::Error> {
+        let info = Command::new("qemu-img")
+            .args(["info", "-U", "--output=json", disk])
+            .output()?;
+
+        if !info.status.success() {
+            return Err(io::Error::other(
+                format!("qemu-img info failed: {}", String::from_utf8_lossy(&info.stderr)),
+            ));
+        }
+
+        let info: serde_json::Value = serde_json::from_slice(&info.stdout)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(info["full-backing-filename"].as_str()
+            .or(info["backing-filename"].as_str())
+            .map(|backing| backing.to_string()))
+    }
+
+    // VM root disks live in LIBVIRT_STORAGE_PATH/<name>/<name>.qcow2, any of
+    // them backed by the base image keeps it in use.
+    pub fn users(file: &str) -> Result<Vec<String>, io::Error> {
+        let base = BaseImage::path(file);
+        let mut users = Vec::new();
+
+        for entry in fs::read_dir(LIBVIRT_STORAGE_PATH)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let disk = format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name);
+            if fs::metadata(&disk).is_err() {
+                continue;
+            }
+
+            if BaseImage::backing_file(&disk)?.as_deref() == Some(base.as_str()) {
+                users.push(name);
+            }
+        }
+
+        Ok(users)
+    }
+
+    // Writing to a base image corrupts every overlay on top of it, once it
+    // backs a disk it is kept read-only and immutable, which also stops root.
+    // Filesystems without support for the attribute only get the mode.
+    pub fn protect(file: &str) -> Result<(), io::Error> {
+        BaseImage::check_file(file)?;
+        fs::set_permissions(BaseImage::path(file), fs::Permissions::from_mode(0o444))?;
+        BaseImage::set_immutable(file, true)
+    }
+
+    fn set_immutable(file: &str, immutable: bool) -> Result<(), io::Error> {
+        let chattr = Command::new("chattr")
+            .args([if immutable { "+i" } else { "-i" }, &BaseImage::path(file)])
+            .output()?;
+
+        if !chattr.status.success() {
+            eprintln!("Warning: Failed to change the immutable attribute of {}: {}", BaseImage::path(file), String::from_utf8_lossy(&chattr.stderr).trim());
+        }
+
+        Ok(())
+    }
+
+    fn lock(file: &str) -> Arc<Mutex<()>> {
+        let mut locks = IMAGE_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+        locks.entry(file.to_string()).or_default().clone()
+    }
+
+    fn remove(file: &str) -> Result<(), io::Error> {
+        BaseImage::set_immutable(file, false)?;
+        fs::remove_file(BaseImage::path(file))
+    }
+
+    pub async fn delete(file: String) -> Result<(), io::Error> {
+        BaseImage::check_file(&file)?;
+        let lock = BaseImage::lock(&file);
+        let _lock = lock.lock().await;
+
+        let users = BaseImage::users(&file)?;
+        if !users.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("Base image {} is in use by: {}", BaseImage::path(&file), users.join(", ")),
+            ));
+        }
+
+        match BaseImage::remove(&file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+
+        let _index_lock = IMAGE_INDEX_LOCK.lock().await;
+        let mut index = BaseImage::read_index();
+        if index.remove(&file).is_some() {
+            BaseImage::write_index(&index)?;
+        }
+
+        Ok(())
+    }
+
+    // The cache index maps image files fetched (or verified) by the agent to
+    // their last use, files placed by hand outside of it are never evicted.
+    fn read_index() -> HashMap<String, u64> {
+        fs::read_to_string(IMAGE_CACHE_INDEX)
+            .ok()
+            .and_then(|index| serde_json::from_str(&index).ok())
+            .unwrap_or_default()
+    }
+
+    fn write_index(index: &HashMap<String, u64>) -> Result<(), io::Error> {
+        fs::write(IMAGE_CACHE_INDEX, serde_json::to_string(index)?)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+    }
+
+    pub fn cached() -> Vec<String> {
+        let mut cached: Vec<String> = BaseImage::read_index()
+            .into_keys()
+            .filter(|file| fs::metadata(BaseImage::path(file)).is_ok())
+            .collect();
+
+        cached.sort();
+        cached
+    }
+
+    // Makes sure the image is in the local cache with the expected checksum,
+    // fetching it from the configured image store if needed.
+    pub async fn ensure(image: &ImageSpec) -> Result<(), io::Error> {
+        BaseImage::check_file(&image.file)?;
+        let lock = BaseImage::lock(&image.file);
+        let _lock = lock.lock().await;
+
+        let config = read_conf_file("config.yaml")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read config file: {}", e)))?;
+
+        let path = BaseImage::path(&image.file);
+        let cached = {
+            let _index_lock = IMAGE_INDEX_LOCK.lock().await;
+            BaseImage::read_index().contains_key(&image.file)
+        };
+
+        // Images placed by hand before the catalog existed have no checksum,
+        // they are used as they are but never fetched.
+        if !cached {
+            if fs::metadata(&path).is_ok() {
+                if !image.checksum.is_empty() {
+                    BaseImage::verify(&path, &image.checksum).await?;
+                }
+            } else {
+                let store = match &config.images {
+                    Some(images) => images.store.trim_end_matches('/').to_string(),
+                    None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Image {} is not cached and no image store is configured", image.file))),
+                };
+
+                BaseImage::fetch(&store, &image.file, &image.checksum).await?;
+            }
+        }
+
+        let _index_lock = IMAGE_INDEX_LOCK.lock().await;
+        let mut index = BaseImage::read_index();
+        index.insert(image.file.clone(), BaseImage::now());
+        BaseImage::write_index(&index)?;
+
+        let cache_size_gb = config.images.and_then(|images| images.cache_size_gb).unwrap_or(DEFAULT_CACHE_SIZE_GB);
+        if let Err(e) = BaseImage::evict(&mut index, &image.file, cache_size_gb * 1024 * 1024 * 1024) {
+            eprintln!("Warning: Failed to evict cached images: {}", e);
+        }
+
+        Ok(())
+    }
+
+    async fn verify(path: &str, checksum: &str) -> Result<(), io::Error> {
+        let (digest, expected) = ImageDigest::new(checksum)?;
+        let actual = BaseImage::hash_file(path, digest).await?;
+        if actual != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Checksum mismatch for {}: expected {}, got {}", path, expected, actual)));
+        }
+
+        Ok(())
+    }
+
+    async fn hash_file(path: &str, mut digest: ImageDigest) -> Result<String, io::Error> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buffer = vec![0; 1024 * 1024];
+
+        loop {
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            digest.update(&buffer[..n]);
+        }
+
+        Ok(digest.finalize())
+    }
+
+    // The root disk comes first in the domain definition, after external
+    // snapshots its source is the newest overlay.
+    fn root_disk_source(domain_xml: &str) -> Option<String> {
+        let disk = domain_xml.split("<disk ").skip(1).find(|disk| disk.starts_with("type='file' device='disk'"))?;
+        let start = disk.find("<source file='")? + "<source file='".len();
+        let end = disk[start..].find('\'')?;
+        Some(disk[start..start + end].to_string())
+    }
+
+    // Turns the disk of a shut off VM into a standalone image in the local
+    // cache. The conversion starts from the active layer, overlays left by
+    // external snapshots and backing files are all merged in. Returns the
+    // checksum the image gets registered with.
+    pub async fn capture(vm: String, tenant: String, file: String, sysprep: bool) -> Result<String, Box<dyn Error>> {
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, vm))?;
+        if domain.is_active()? {
+            return Err(Box::new(io::Error::new(io::ErrorKind::ResourceBusy, format!("VM {} must be shut off to be captured", vm))));
+        }
+        BaseImage::check_file(&file)?;
+
+        let lock = BaseImage::lock(&file);
+        let _lock = lock.lock().await;
+
+        let path = BaseImage::path(&file);
+        if fs::metadata(&path).is_ok() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::AlreadyExists, format!("Image {} already exists", path))));
+        }
+
+        let partial = format!("{}.part", path);
+        let disk = match BaseImage::root_disk_source(&domain.get_xml_desc(0)?) {
+            Some(disk) => disk,
+            None => format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, vm, vm),
+        };
+        let convert_disk = Command::new("qemu-img")
+            .args(["convert", "-f", "qcow2", "-O", "qcow2", &disk, &partial])
+            .output()?;
+
+        if !convert_disk.status.success() {
+            let _ = fs::remove_file(&partial);
+            return Err(Box::new(io::Error::other(
+                format!("qemu-img convert failed: {}", String::from_utf8_lossy(&convert_disk.stderr)),
+            )));
+        }
+
+        // Instance specific state would otherwise be shared by every VM
+        // created from the image, cloud-init has to run again on first boot.
+        if sysprep {
+            let sysprep_disk = Command::new("virt-sysprep")
+                .args([
+                    "-a", &partial,
+                    "--operations", "machine-id,ssh-hostkeys,net-hwaddr,udev-persistent-net,logfiles,bash-history,tmp-files",
+                    "--delete", "/var/lib/cloud/*",
+                ])
+                .output()?;
+
+            if !sysprep_disk.status.success() {
+                let _ = fs::remove_file(&partial);
+                return Err(Box::new(io::Error::other(
+                    format!("virt-sysprep failed: {}", String::from_utf8_lossy(&sysprep_disk.stderr)),
+                )));
+            }
+        }
+
+        let checksum = BaseImage::hash_file(&partial, ImageDigest::Sha256(Sha256::new())).await?;
+        fs::rename(&partial, &path)?;
+
+        let _index_lock = IMAGE_INDEX_LOCK.lock().await;
+        let mut index = BaseImage::read_index();
+        index.insert(file, BaseImage::now());
+        BaseImage::write_index(&index)?;
+
+        Ok(format!("sha256:{}", checksum))
+    }
+
+    // Lets the controlplane copy captured images into its image store, it
+    // authenticates with the agent token.
+    pub async fn open(file: &str) -> Result<tokio::fs::File, io::Error> {
+        BaseImage::check_file(file)?;
+        if !BaseImage::read_index().contains_key(file) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("Image {} is not cached", file)));
+        }
+
+        tokio::fs::File::open(BaseImage::path(file)).await
+    }
+
+    // Downloads into a temporary file hashed on the fly, it is only renamed
+    // into place once the checksum matches.
+    async fn fetch(store: &str, file: &str, checksum: &str) -> Result<(), io::Error> {
+        let path = BaseImage::path(file);
+        let partial = format!("{}.part", path);
+
+        if let Some(directory) = store.strip_prefix("file://") {
+            tokio::fs::copy(format!("{}/{}", directory, file), &partial).await?;
+        } else {
+            // Private images are only handed out to hypervisors.
+            let mut request = reqwest::Client::new().get(format!("{}/{}", store, file));
+            if let Ok(token) = VmMigration::agent_token() {
+                request = request.header("X-AWP-Agent-Token", token);
+            }
+
+            let mut response = request.send().await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| io::Error::other(format!("Failed to download image {}: {}", file, e)))?;
+
+            let mut output = tokio::fs::File::create(&partial).await?;
+            while let Some(chunk) = response.chunk().await.map_err(|e| io::Error::other(format!("Failed to download image {}: {}", file, e)))? {
+                output.write_all(&chunk).await?;
+            }
+            output.flush().await?;
+        }
+
+        if let Err(e) = BaseImage::verify(&partial, checksum).await {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+
+        fs::rename(&partial, &path)
+    }
+
+    // Least recently used images go first, images still backing VM disks,
+    // busy ones and the one just requested are kept even if the cache stays
+    // too big.
+    fn evict(index: &mut HashMap<String, u64>, keep: &str, max_size: u64) -> Result<(), io::Error> {
+        let mut entries: Vec<(String, u64, u64)> = index.iter()
+            .filter_map(|(file, last_used)| fs::metadata(BaseImage::path(file)).ok().map(|meta| (file.clone(), *last_used, meta.len())))
+            .collect();
+
+        let mut total_size: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        entries.sort_by_key(|(_, last_used, _)| *last_used);
+
+        for (file, _, size) in entries {
+            if total_size <= max_size {
+                break;
+            }
+
+            if file == keep {
+                continue;
+            }
+
+            let lock = BaseImage::lock(&file);
+            let _lock = match lock.try_lock() {
+                Ok(lock) => lock,
+                Err(_) => continue,
+            };
+
+            if !BaseImage::users(&file)?.is_empty() {
+                continue;
+            }
+
+            BaseImage::remove(&file)?;
+            index.remove(&file);
+            total_size -= size;
+            println!("Evicted cached image {} ({} bytes)", file, size);
+        }
+
+        BaseImage::write_index(index)
+    }
+}
@@ -2,36 +2,199 @@
 // GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
 
 use std::io;
-use virt::connect::Connect;
+use virt::connect::{Connect, NodeInfo};
 use virt::domain::Domain;
-use virt::sys::VIR_DOMAIN_UNDEFINE_NVRAM;
+use virt::sys::{VIR_DOMAIN_UNDEFINE_NVRAM, VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA, VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_AFFECT_LIVE};
+use virt::sys::{VIR_DOMAIN_VCPU_LIVE, VIR_DOMAIN_VCPU_CONFIG, VIR_DOMAIN_VCPU_MAXIMUM, VIR_DOMAIN_MEM_LIVE, VIR_DOMAIN_MEM_CONFIG, VIR_DOMAIN_MEM_MAXIMUM};
+use virt::sys::VIR_DOMAIN_XML_INACTIVE;
 use std::process::Command;
 use crate::api::ovs;
+use crate::api::image::{BaseImage, ImageSpec};
+use crate::api::volume::RESERVED_VM_NAME;
+use crate::api::migration::MigrationConfig;
 use std::fs;
 use indoc::indoc;
 use std::error::Error;
+use std::collections::HashSet;
 
 
-static LIBVIRT_STORAGE_PATH: &str = "/var/lib/libvirt/images";
+pub(crate) static LIBVIRT_STORAGE_PATH: &str = "/var/lib/libvirt/images";
+static STOP_TIMEOUT_SECS: u64 = 120;
 
 pub struct VmDomain {}
 
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct Config {
+    hotplug: Option<HotplugConfig>,
+    pub(crate) migration: Option<MigrationConfig>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct HotplugConfig {
+    max_vcpus: Option<u32>,
+    max_memory_gb: Option<u64>,
+}
+
+pub(crate) fn read_conf_file(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config)
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct NetworkConfig {
+    address: String,
+    gateway: Option<String>,
+    dns_servers: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Bandwidth {
+    ingress_kbps: Option<u32>,
+    egress_kbps: Option<u32>,
+    burst_kbit: Option<u32>,
+}
+
+impl Bandwidth {
+    // libvirt expresses rates in KiB/s and bursts in KiB, inbound being the
+    // traffic received by the guest.
+    fn to_xml(&self) -> String {
+        let burst = self.burst_kbit
+            .map(|burst| format!(" burst='{}'", burst.div_ceil(8)))
+            .unwrap_or_default();
+
+        let mut xml = String::new();
+        if let Some(rate) = self.ingress_kbps {
+            xml += &format!("<inbound average='{}'{}/>", rate.div_ceil(8), burst);
+        }
+        if let Some(rate) = self.egress_kbps {
+            xml += &format!("<outbound average='{}'{}/>", rate.div_ceil(8), burst);
+        }
+
+        format!("<bandwidth>{}</bandwidth>", xml)
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+pub struct ExtraSpecs {
+    #[serde(default)]
+    hugepages: bool,
+    #[serde(default)]
+    cpu_pinning: bool,
+}
+
+impl ExtraSpecs {
+    fn is_empty(&self) -> bool {
+        !self.hugepages && !self.cpu_pinning
+    }
+
+    // Host CPUs dedicated to the vCPUs of other domains.
+    fn pinned_host_cpus(conn: &Connect, exclude: &str) -> Result<HashSet<u32>, virt::error::Error> {
+        let mut pinned = HashSet::new();
+        for domain in conn.list_all_domains(0)? {
+            if domain.get_name()? == exclude {
+                continue;
+            }
+
+            let domain_xml = domain.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?;
+            for vcpupin in domain_xml.split("<vcpupin ").skip(1) {
+                let cpuset = vcpupin.split("cpuset='").nth(1).and_then(|cpuset| cpuset.split('\'').next());
+                if let Some(cpu) = cpuset.and_then(|cpuset| cpuset.parse().ok()) {
+                    pinned.insert(cpu);
+                }
+            }
+        }
+
+        Ok(pinned)
+    }
+
+    // Hugepage backed memory cannot be ballooned and hotplugged vCPUs would
+    // float across the host, domains using either lose their hotplug headroom.
+    // Each vCPU gets a host CPU of its own, CPU 0 is left to the host.
+    fn apply(&self, conn: &Connect, domain: &Domain, cpu: u32, memory: u64) -> Result<Domain, Box<dyn Error>> {
+        domain.set_vcpus_flags(cpu, VIR_DOMAIN_VCPU_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+        domain.set_memory_flags(memory * 1024, VIR_DOMAIN_MEM_CONFIG | VIR_DOMAIN_MEM_MAXIMUM)?;
+
+        let mut tuning_xml = String::new();
+        if self.hugepages {
+            tuning_xml += "<memoryBacking><hugepages/></memoryBacking>";
+        }
+
+        if self.cpu_pinning {
+            let pinned = ExtraSpecs::pinned_host_cpus(conn, &domain.get_name()?)?;
+            let free: Vec<u32> = (1..conn.get_node_info()?.cpus)
+                .filter(|host_cpu| !pinned.contains(host_cpu))
+                .take(cpu as usize)
+                .collect();
+
+            if free.len() < cpu as usize {
+                return Err(Box::new(io::Error::other(format!("Only {} host CPUs are left for pinning, {} requested", free.len(), cpu))));
+            }
+
+            let vcpupin_xml: String = free.iter().enumerate()
+                .map(|(vcpu, host_cpu)| format!("<vcpupin vcpu='{}' cpuset='{}'/>", vcpu, host_cpu))
+                .collect();
+            tuning_xml += &format!("<cputune>{}</cputune>", vcpupin_xml);
+        }
+
+        let domain_xml = domain.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?.replacen("<devices>", &format!("{}<devices>", tuning_xml), 1);
+        Ok(Domain::define_xml(conn, &domain_xml)?)
+    }
+}
+
+// vCPUs and memory (MiB) of a domain along with their hotplug maximums.
+struct DomainSize {
+    cpu: u32,
+    max_cpu: u32,
+    memory: u64,
+    max_memory: u64,
+}
+
+impl DomainSize {
+    // Domains get room to hotplug vCPUs and balloon memory up to the
+    // configured maximums capped to the host size, resizes past them wait
+    // for the next boot. Hugepages back the whole maximum and pinned vCPUs
+    // never change, those domains get no room.
+    fn new(cpu: u32, memory: u64, extra_specs: &ExtraSpecs, host: &NodeInfo) -> Self {
+        let hotplug = read_conf_file("config.yaml").ok().and_then(|config| config.hotplug);
+        let max_cpu = match extra_specs.cpu_pinning {
+            true => cpu,
+            false => hotplug.as_ref().and_then(|hotplug| hotplug.max_vcpus).unwrap_or(0).min(host.cpus).max(cpu),
+        };
+        let max_memory = match extra_specs.hugepages {
+            true => memory,
+            false => hotplug.and_then(|hotplug| hotplug.max_memory_gb).map(|max| max * 1024).unwrap_or(0).min(host.memory / 1024).max(memory),
+        };
+
+        DomainSize { cpu, max_cpu, memory, max_memory }
+    }
+}
+
 impl VmDomain {
     async fn generate_domain_xml(
       name: &str, 
-      memory: &u64, 
-      cpu: &u32, 
+      size: &DomainSize, 
       tenant: &str, 
       mac_addr: &str, 
-      arch: &str) -> String {
+      bandwidth: Option<&Bandwidth>,
+      cloud_init: bool) -> String {
         
+      let bandwidth_xml = bandwidth.map(|bandwidth| bandwidth.to_xml()).unwrap_or_default();
+      // Images without cloud-init get no seed ISO attached.
+      let seed_xml = if cloud_init {
+          format!("<disk type='file' device='cdrom'><driver name='qemu' type='raw'/><source file='{}/{}/seed.iso' index='1'/><backingStore/><target dev='sda' bus='sata'/><readonly/><alias name='sata0-0-0'/><address type='drive' controller='0' bus='0' target='0' unit='0'/></disk>", LIBVIRT_STORAGE_PATH, name)
+      } else {
+          String::new()
+      };
+      let arch = std::env::consts::ARCH;
       let mut domain_xml = String::new();
       if arch == "aarch64" {
         domain_xml = format!(r"
         <domain type='kvm'>
           <name>{}-{}</name>
           <memory unit='KiB'>{}</memory>
-          <vcpu placement='static'>{}</vcpu>
+          <currentMemory unit='KiB'>{}</currentMemory>
+          <vcpu placement='static' current='{}'>{}</vcpu>
           <os firmware='efi'>
             <type arch='aarch64' machine='virt-7.2'>hvm</type>
             <firmware>
@@ -51,19 +214,12 @@ impl VmDomain {
               <target dev='vda' bus='virtio'/>
               <address type='pci' slot='0x04'/>
             </disk>
-            <disk type='file' device='cdrom'>
-              <driver name='qemu' type='raw'/>
-              <source file='{}/{}/seed.iso' index='1'/>
-              <backingStore/>
-              <target dev='sda' bus='sata'/>
-              <readonly/>
-              <alias name='sata0-0-0'/>
-              <address type='drive' controller='0' bus='0' target='0' unit='0'/>
-            </disk>
+            {}
             <interface type='ethernet'>
                 <mac address='{}'/>
                 <target dev='{}-{}'/>
                 <model type='virtio'/>
+                {}
             </interface>
             <console type='pty'>
             <target type='serial' port='0'/>
@@ -79,13 +235,14 @@ impl VmDomain {
             </channel>
           </devices>
         </domain>
-        ", tenant, name, memory * 1024, cpu, LIBVIRT_STORAGE_PATH, name, name, LIBVIRT_STORAGE_PATH, name, mac_addr, tenant, name);
+        ", tenant, name, size.max_memory * 1024, size.memory * 1024, size.cpu, size.max_cpu, LIBVIRT_STORAGE_PATH, name, name, seed_xml, mac_addr, tenant, name, bandwidth_xml);
       } else if arch == "x86_64" {
         domain_xml = format!(r"
         <domain type='kvm'>
           <name>{}-{}</name>
           <memory unit='KiB'>{}</memory>
-          <vcpu placement='static'>{}</vcpu>
+          <currentMemory unit='KiB'>{}</currentMemory>
+          <vcpu placement='static' current='{}'>{}</vcpu>
           <os>
             <type arch='x86_64' machine='q35'>hvm</type>
           </os>
@@ -100,19 +257,12 @@ impl VmDomain {
               <target dev='vda' bus='virtio'/>
               <address type='pci' slot='0x04'/>
             </disk>
-            <disk type='file' device='cdrom'>
-              <driver name='qemu' type='raw'/>
-              <source file='{}/{}/seed.iso' index='1'/>
-              <backingStore/>
-              <target dev='sda' bus='sata'/>
-              <readonly/>
-              <alias name='sata0-0-0'/>
-              <address type='drive' controller='0' bus='0' target='0' unit='0'/>
-            </disk>
+            {}
             <interface type='ethernet'>
                 <mac address='{}'/>
                 <target dev='{}-{}'/>
                 <model type='virtio'/>
+                {}
             </interface>
             <console type='pty'>
             <target type='serial' port='0'/>
@@ -128,7 +278,7 @@ impl VmDomain {
             </channel>
           </devices>
         </domain>
-        ", tenant, name, memory * 1024, cpu, LIBVIRT_STORAGE_PATH, name, name, LIBVIRT_STORAGE_PATH, name, mac_addr, tenant, name);
+        ", tenant, name, size.max_memory * 1024, size.memory * 1024, size.cpu, size.max_cpu, LIBVIRT_STORAGE_PATH, name, name, seed_xml, mac_addr, tenant, name, bandwidth_xml);
       }
 
       return domain_xml;
@@ -138,53 +288,94 @@ impl VmDomain {
         name: String, 
         memory: u64, 
         cpu: u32, 
-        os: String, 
+        image: ImageSpec, 
         pub_key: String,
         disk_size: u32, 
         tenant: String, 
         mac_addr: String, 
         networking: String, 
-        network: Option<String>
+        fqdn: Option<String>,
+        network_config: Option<NetworkConfig>,
+        bandwidth: Option<Bandwidth>,
+        copy_on_write: bool,
+        extra_specs: ExtraSpecs,
+        reuse_disk: bool
     ) -> Result<Domain, Box<dyn Error>> {
         let conn: Connect = Connect::open(Some("qemu:///system"))?;
 
-        let arch = std::env::consts::ARCH.to_string();
-        let domain_xml = VmDomain::generate_domain_xml(&name, &memory, &cpu, &tenant, &mac_addr, &arch).await;
+        let size = DomainSize::new(cpu, memory, &extra_specs, &conn.get_node_info()?);
+        let domain_xml = VmDomain::generate_domain_xml(&name, &size, &tenant, &mac_addr, bandwidth.as_ref(), image.cloud_init).await;
 
-        VmDomain::create_disk(&os, &name, &disk_size)?;
-        VmDomain::generate_seed(&pub_key, &name)?;
-        let domain: Domain = Domain::define_xml(&conn, &domain_xml)?;
+        BaseImage::ensure(&image).await?;
+        // Disks on shared storage outlive their hypervisor, VMs evacuated from
+        // it boot again from their disk and seed as they were left.
+        if reuse_disk {
+            let disk = format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name);
+            if !std::path::Path::new(&disk).exists() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("Disk '{}' not found, it is not on shared storage", disk)).into());
+            }
+        } else {
+            VmDomain::create_disk(&image, &name, &disk_size, copy_on_write)?;
+            let fqdn = fqdn.unwrap_or(name.clone());
+            if image.cloud_init {
+                VmDomain::generate_seed(&pub_key, &name, &fqdn, &mac_addr, &image.default_user, network_config.as_ref())?;
+            }
+        }
+        let mut domain: Domain = Domain::define_xml(&conn, &domain_xml)?;
+        if !extra_specs.is_empty() {
+            domain = extra_specs.apply(&conn, &domain, cpu, memory)?;
+        }
         domain.create()?;
         domain.set_autostart(true)?;
 
-        if networking == "l2-tenant" {
-            VmDomain::create_vm_nic(&name, tenant).await?;
-        } else if networking == "l2-bridged" {
-          match network {
-              Some(network) => {
-                  let bridge_name = format!("br-vlan{}", network);
-                  VmDomain::add_vnet_to_provider_bridge(&name, &bridge_name, &tenant).await?;
-            }
-            None => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Network name is required for l2-bridged networking").into());
-            }
-          }
+        // Both tenant and provider networks are OVN logical switches, provider
+        // ones reach the physical network through their localnet port.
+        match networking.as_str() {
+            "l2-tenant" | "l2-bridged" => VmDomain::create_vm_nic(&name, tenant).await?,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported networking mode '{}'", networking)).into()),
         }
 
         return Ok(domain)
     }
 
-    async fn add_vnet_to_provider_bridge(name: &str, bridge_name: &str, tenant: &str) -> Result<(), io::Error> {
-        ovs::OvsDbRequest::add_vnet_to_provider_bridge(name, bridge_name, tenant).await?;
-        Ok(())
-    }
-
     async fn create_vm_nic(name: &str, tenant: String) -> Result<(), io::Error> {
         ovs::OvsDbRequest::add_port(name, None, tenant).await?;
         Ok(())
     }
 
-    fn generate_seed(pub_key: &str, name: &str) -> Result<(), io::Error> {
+    fn generate_network_config(mac_addr: &str, network_config: &NetworkConfig) -> String {
+        let mut config = format!(indoc!{r#"
+        version: 2
+        ethernets:
+          eth0:
+            match:
+              macaddress: "{}"
+            set-name: eth0
+            dhcp4: false
+            addresses:
+              - {}
+        "#}, mac_addr, network_config.address);
+
+        // Appended fragments are nested under eth0, indoc would strip the indentation.
+        if let Some(gateway) = &network_config.gateway {
+            config += &format!("    routes:\n      - to: default\n        via: {}\n", gateway);
+        }
+
+        if !network_config.dns_servers.is_empty() {
+            config += &format!("    nameservers:\n      addresses: [{}]\n", network_config.dns_servers.join(", "));
+        }
+
+        config
+    }
+
+    fn generate_seed(
+        pub_key: &str,
+        name: &str,
+        fqdn: &str,
+        mac_addr: &str,
+        default_user: &str,
+        network_config: Option<&NetworkConfig>
+    ) -> Result<(), io::Error> {
         let user_data = format!(indoc!{ r#"
         #cloud-config
         ssh_authorized_keys:
@@ -192,29 +383,42 @@ impl VmDomain {
         shell: /bin/bash
         chpasswd:
           list: |
-            cloud-user:temppassword123
+            {}:temppassword123
           expire: False
-        "#}, pub_key);
+        hostname: {}
+        fqdn: {}
+        manage_etc_hosts: true
+        growpart:
+          mode: auto
+          devices: ['/']
+        resize_rootfs: true
+        "#}, pub_key, default_user, name, fqdn);
 
         let meta_data: String = format!(indoc!{r#"
         instance-id: {}
-        local-hostname: localhost.localdomain
-        "#}, name);
+        local-hostname: {}
+        "#}, name, fqdn);
 
         fs::write(format!("{}/{}/user-data", LIBVIRT_STORAGE_PATH, name), user_data)?;
         fs::write(format!("{}/{}/meta-data", LIBVIRT_STORAGE_PATH, name), meta_data)?;
 
+        let mut seed_files = vec!["user-data", "meta-data"];
+        if let Some(network_config) = network_config {
+            let network_config = VmDomain::generate_network_config(mac_addr, network_config);
+            fs::write(format!("{}/{}/network-config", LIBVIRT_STORAGE_PATH, name), network_config)?;
+            seed_files.push("network-config");
+        }
+
         let create_seed = Command::new("xorriso")
-            .args(&[
+            .args([
                 "-as",
                 "mkisofs",
                 "-output", &format!("{}/{}/seed.iso", LIBVIRT_STORAGE_PATH, name),
                 "-volid", "CIDATA",
                 "-joliet",
                 "-rock",
-                &format!("{}/{}/user-data", LIBVIRT_STORAGE_PATH, name),
-                &format!("{}/{}/meta-data", LIBVIRT_STORAGE_PATH, name),
             ])
+            .args(seed_files.iter().map(|file| format!("{}/{}/{}", LIBVIRT_STORAGE_PATH, name, file)))
             .output()?;
 
         if !create_seed.status.success() {
@@ -224,7 +428,7 @@ impl VmDomain {
           ));
         }
 
-        for file in vec!["user-data", "meta-data"] {
+        for file in seed_files {
             let remove_file = fs::remove_file(format!("{}/{}/{}", LIBVIRT_STORAGE_PATH, name, file));
             if remove_file.is_err() {
                 println!("Failed to remove user,meta-data: {}", remove_file.unwrap_err());
@@ -234,23 +438,29 @@ impl VmDomain {
         Ok(())
     }
     
-    fn create_disk(os: &str, name: &str, size: &u32) -> Result<(), io::Error> {
+    fn create_disk(image: &ImageSpec, name: &str, size: &u32, copy_on_write: bool) -> Result<(), io::Error> {
+        VmDomain::check_vm_name(name)?;
         fs::create_dir(format!("{}/{}", LIBVIRT_STORAGE_PATH, name))?;
-      
-        let create_disk = Command::new("qemu-img")
-            .args(&[
-                "convert",
-                "-f", "qcow2",
-                "-O", "qcow2",
-                &format!("{}/{}-base.qcow2", LIBVIRT_STORAGE_PATH, os.to_uppercase()),
-                &format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name),
-            ])
-            .output()?;
+
+        let base_image = BaseImage::path(&image.file);
+        let disk = format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name);
+
+        // Overlays only store the blocks the VM writes, reads of untouched
+        // blocks fall through to the shared base image.
+        let create_disk = if copy_on_write {
+            BaseImage::protect(&image.file)?;
+            Command::new("qemu-img")
+                .args(["create", "-f", "qcow2", "-F", &image.format, "-b", &base_image, &disk])
+                .output()?
+        } else {
+            Command::new("qemu-img")
+                .args(["convert", "-f", &image.format, "-O", "qcow2", &base_image, &disk])
+                .output()?
+        };
     
         if !create_disk.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("qemu-img convert failed: {}", String::from_utf8_lossy(&create_disk.stderr)),
+            return Err(io::Error::other(
+                format!("qemu-img {} failed: {}", if copy_on_write { "create" } else { "convert" }, String::from_utf8_lossy(&create_disk.stderr)),
             ));
         }
     
@@ -272,7 +482,133 @@ impl VmDomain {
         Ok(())
     }    
 
+    // Copies the data still held by the base image into the VM disk so that
+    // it no longer depends on it. qemu-img needs exclusive access to the
+    // disk, the domain has to be shut off.
+    pub async fn flatten_disk(name: String, tenant: String) -> Result<(), Box<dyn Error>> {
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, name))?;
+
+        if domain.is_active()? {
+            return Err(Box::new(io::Error::new(io::ErrorKind::ResourceBusy, format!("VM {} must be shut off to be flattened", name))));
+        }
+
+        let disk = format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name);
+        if BaseImage::backing_file(&disk)?.is_none() {
+            return Ok(());
+        }
+
+        let rebase_disk = Command::new("qemu-img")
+            .args(["rebase", "-f", "qcow2", "-b", "", &disk])
+            .output()?;
+
+        if !rebase_disk.status.success() {
+            return Err(Box::new(io::Error::other(
+                format!("qemu-img rebase failed: {}", String::from_utf8_lossy(&rebase_disk.stderr)),
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Returns whether the new size is already seen by the guest, otherwise
+    // it is only applied to the persistent definition and takes effect on
+    // the next boot. Unplugging vCPUs needs guest cooperation and is never
+    // attempted live.
+    pub async fn resize_vm(name: String, tenant: String, cpu: u32, memory: u64) -> Result<bool, Box<dyn Error>> {
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, name))?;
+
+        let memory_kib = memory * 1024;
+        let max_cpu = domain.get_vcpus_flags(VIR_DOMAIN_VCPU_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+        let max_memory_kib = domain.get_max_memory()?;
+
+        if domain.is_active()? && cpu <= max_cpu && memory_kib <= max_memory_kib
+            && cpu >= domain.get_vcpus_flags(VIR_DOMAIN_VCPU_LIVE)? {
+            domain.set_vcpus_flags(cpu, VIR_DOMAIN_VCPU_LIVE | VIR_DOMAIN_VCPU_CONFIG)?;
+            domain.set_memory_flags(memory_kib, VIR_DOMAIN_MEM_LIVE | VIR_DOMAIN_MEM_CONFIG)?;
+            return Ok(true);
+        }
+
+        if cpu > max_cpu {
+            domain.set_vcpus_flags(cpu, VIR_DOMAIN_VCPU_CONFIG | VIR_DOMAIN_VCPU_MAXIMUM)?;
+        }
+        domain.set_vcpus_flags(cpu, VIR_DOMAIN_VCPU_CONFIG)?;
+
+        if memory_kib > max_memory_kib {
+            domain.set_memory_flags(memory_kib, VIR_DOMAIN_MEM_CONFIG | VIR_DOMAIN_MEM_MAXIMUM)?;
+        }
+        domain.set_memory_flags(memory_kib, VIR_DOMAIN_MEM_CONFIG)?;
+
+        Ok(false)
+    }
+
+    pub async fn resize_vm_disk(name: String, tenant: String, size: u32) -> Result<(), Box<dyn Error>> {
+        let disk = format!("{}/{}/{}.qcow2", LIBVIRT_STORAGE_PATH, name, name);
+        VmDomain::resize_disk(&tenant, Some(&name), &disk, size)
+    }
+
+    // Disks only ever grow, the guest filesystem would otherwise be cut off.
+    // Running domains get the new size through a live block resize, qemu-img
+    // holds the image lock and can only be used on stopped ones.
+    pub fn resize_disk(tenant: &str, vm: Option<&str>, disk: &str, size: u32) -> Result<(), Box<dyn Error>> {
+        let requested = u64::from(size) * 1024 * 1024 * 1024;
+        if requested < VmDomain::disk_virtual_size(disk)? {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Shrinking disk {} is not supported", disk))));
+        }
+
+        if let Some(vm) = vm {
+            let conn = Connect::open(Some("qemu:///system"))?;
+            let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, vm))?;
+
+            if domain.is_active()? {
+                // Without VIR_DOMAIN_BLOCK_RESIZE_BYTES the size is in KiB.
+                domain.block_resize(disk, requested / 1024, 0)?;
+                return Ok(());
+            }
+        }
+
+        let resize_disk = Command::new("qemu-img")
+            .args(["resize", "-f", "qcow2", disk, &format!("{}G", size)])
+            .output()?;
+
+        if !resize_disk.status.success() {
+            return Err(Box::new(io::Error::other(
+                format!("qemu-img resize failed: {}", String::from_utf8_lossy(&resize_disk.stderr)),
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn disk_virtual_size(disk: &str) -> Result<u64, io::Error> {
+        let info = Command::new("qemu-img")
+            .args(["info", "-U", "--output=json", disk])
+            .output()?;
+
+        if !info.status.success() {
+            return Err(io::Error::other(
+                format!("qemu-img info failed: {}", String::from_utf8_lossy(&info.stderr)),
+            ));
+        }
+
+        let info: serde_json::Value = serde_json::from_slice(&info.stdout)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        info["virtual-size"].as_u64()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No virtual size reported for {}", disk)))
+    }
+
+    fn check_vm_name(name: &str) -> Result<(), io::Error> {
+        if name == RESERVED_VM_NAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("VM name '{}' is reserved", name)));
+        }
+
+        Ok(())
+    }
+
     fn remove_vm_dir(name: &str) -> Result<(), io::Error> {
+        VmDomain::check_vm_name(name)?;
         let remove_vm_dir = fs::remove_dir_all(
             format!("{}/{}", LIBVIRT_STORAGE_PATH, name)
         );
@@ -287,18 +623,94 @@ impl VmDomain {
         Ok(())
     }
 
+    fn domain_mac_address(domain_xml: &str) -> Option<String> {
+        let start = domain_xml.find("<mac address='")? + "<mac address='".len();
+        let end = domain_xml[start..].find('\'')?;
+        Some(domain_xml[start..start + end].to_string())
+    }
+
+    pub async fn set_bandwidth(name: String, tenant: String, bandwidth: Bandwidth) -> Result<(), Box<dyn Error>> {
+      let conn = Connect::open(Some("qemu:///system"))?;
+      let domain_name = format!("{}-{}", tenant, name);
+      let domain = Domain::lookup_by_name(&conn, &domain_name)?;
+
+      // libvirt matches the interface to update by its MAC address.
+      let mac_addr = VmDomain::domain_mac_address(&domain.get_xml_desc(0)?)
+          .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No interface found for domain {}", domain_name)))?;
+
+      let interface_xml = format!(
+          "<interface type='ethernet'><mac address='{}'/><target dev='{}'/><model type='virtio'/>{}</interface>",
+          mac_addr, domain_name, bandwidth.to_xml()
+      );
+
+      let mut flags = VIR_DOMAIN_AFFECT_CONFIG;
+      if domain.is_active()? {
+          flags |= VIR_DOMAIN_AFFECT_LIVE;
+      }
+
+      domain.update_device_flags(&interface_xml, flags)?;
+      Ok(())
+    }
+
+    pub fn list_domain_names() -> Result<Vec<String>, virt::error::Error> {
+      let conn = Connect::open(Some("qemu:///system"))?;
+      let domains = conn.list_all_domains(0)?;
+
+      domains.iter().map(|domain| domain.get_name()).collect()
+    }
+
+    // Guests get STOP_TIMEOUT_SECS to shut down cleanly before being powered off.
+    pub async fn stop_vm(name: String, tenant: String) -> Result<(), virt::error::Error> {
+      let conn = Connect::open(Some("qemu:///system"))?;
+      let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, name))?;
+
+      if !domain.is_active()? {
+          return Ok(());
+      }
+
+      domain.shutdown()?;
+      for _ in 0..STOP_TIMEOUT_SECS {
+          tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+          if !domain.is_active()? {
+              return Ok(());
+          }
+      }
+
+      domain.destroy()?;
+      Ok(())
+    }
+
     pub async fn delete_vm(name: String, tenant: String) -> Result<(), virt::error::Error> {
       let conn = Connect::open(Some("qemu:///system"))?;
       let domain_name = format!("{}-{}", tenant, name);
 
-      let domain = Domain::lookup_by_name(&conn, &domain_name)?;
+      // A create that failed halfway leaves a disk or port without a domain,
+      // the controlplane relies on delete to clean those up too.
+      let domain = Domain::lookup_by_name(&conn, &domain_name).ok();
   
       if let Err(e) = VmDomain::remove_vm_dir(&name) {
           eprintln!("Warning: Failed to remove VM directory: {:?}", e);
       }
   
-      domain.destroy()?;  
-      domain.undefine_flags(VIR_DOMAIN_UNDEFINE_NVRAM)?;
+      VmDomain::remove_domain(domain, name, tenant).await
+    }
+
+    // Drops the domain and its port but keeps the disk, for VMs that were
+    // evacuated to another hypervisor from shared storage.
+    pub async fn undefine_vm(name: String, tenant: String) -> Result<(), virt::error::Error> {
+      let conn = Connect::open(Some("qemu:///system"))?;
+      let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, name)).ok();
+
+      VmDomain::remove_domain(domain, name, tenant).await
+    }
+
+    async fn remove_domain(domain: Option<Domain>, name: String, tenant: String) -> Result<(), virt::error::Error> {
+      if let Some(domain) = domain {
+          if domain.is_active()? {
+              domain.destroy()?;
+          }
+          domain.undefine_flags(VIR_DOMAIN_UNDEFINE_NVRAM | VIR_DOMAIN_UNDEFINE_SNAPSHOTS_METADATA)?;
+      }
   
       if let Err(e) = ovs::OvsDbRequest::delete_port(name, None, tenant).await {
           eprintln!("Warning: Failed to delete OVS port: {:?}", e);
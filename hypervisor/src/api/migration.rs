@@ -0,0 +1,401 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use std::io;
+use std::fs;
+use std::error::Error;
+use std::process::Command;
+use reqwest::Client;
+use gethostname::gethostname;
+use tokio::io::AsyncWriteExt;
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt::domain::MigrateParameters;
+use virt::sys::{VIR_DOMAIN_XML_INACTIVE, VIR_DOMAIN_JOB_NONE};
+use virt::sys::{VIR_MIGRATE_LIVE, VIR_MIGRATE_PEER2PEER, VIR_MIGRATE_PERSIST_DEST, VIR_MIGRATE_UNDEFINE_SOURCE};
+use virt::sys::{VIR_MIGRATE_NON_SHARED_DISK, VIR_MIGRATE_NON_SHARED_INC, VIR_MIGRATE_ABORT_ON_ERROR, VIR_MIGRATE_AUTO_CONVERGE};
+use crate::api::ovs;
+use crate::api::libvirt::{VmDomain, LIBVIRT_STORAGE_PATH, read_conf_file};
+use crate::api::image::{BaseImage, ImageSpec};
+
+
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct MigrationConfig {
+    agent_token: Option<String>,
+    transport: Option<String>,
+    shared_storage: Option<bool>,
+}
+
+// What the target needs to recreate a shut off VM: its inactive definition
+// and the files of its directory, disk and cloud-init seed.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct VmExport {
+    pub domain_xml: String,
+    pub files: Vec<String>,
+}
+
+pub struct VmMigration {}
+
+impl VmMigration {
+    // Agents authenticate each other with the token shared in their config,
+    // sent in the X-AWP-Agent-Token header. Without a configured token no
+    // VM can be migrated off this hypervisor.
+    pub(crate) fn agent_token() -> Result<String, io::Error> {
+        read_conf_file("config.yaml").ok()
+            .and_then(|config| config.migration)
+            .and_then(|migration| migration.agent_token)
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "No agent token configured for migrations"))
+    }
+
+    pub fn authorize(token: Option<&str>) -> Result<(), io::Error> {
+        if token != Some(VmMigration::agent_token()?.as_str()) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Invalid agent token"));
+        }
+
+        Ok(())
+    }
+
+    // libvirtd on the source connects to the target one, qemu+tcp unless
+    // configured otherwise.
+    fn libvirt_uri(target: &str) -> String {
+        let transport = read_conf_file("config.yaml").ok()
+            .and_then(|config| config.migration)
+            .and_then(|migration| migration.transport)
+            .unwrap_or("tcp".to_string());
+
+        format!("qemu+{}://{}/system", transport, target)
+    }
+
+    // Whether LIBVIRT_STORAGE_PATH is shared between hypervisors, only the
+    // config says so, a directory left behind by an earlier VM looks the same.
+    fn shared_storage() -> bool {
+        read_conf_file("config.yaml").ok()
+            .and_then(|config| config.migration)
+            .and_then(|migration| migration.shared_storage)
+            .unwrap_or(false)
+    }
+
+    // VMs are only ever moved into a directory of their own, anything already
+    // there belongs to something else.
+    fn create_vm_dir(name: &str) -> Result<(), io::Error> {
+        fs::create_dir(VmMigration::vm_dir(name)).map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(io::ErrorKind::AlreadyExists, format!("Directory of VM {} already exists on this hypervisor", name)),
+            _ => e,
+        })
+    }
+
+    fn vm_dir(name: &str) -> String {
+        format!("{}/{}", LIBVIRT_STORAGE_PATH, name)
+    }
+
+    fn vm_disk(name: &str) -> String {
+        format!("{}/{}.qcow2", VmMigration::vm_dir(name), name)
+    }
+
+    fn lookup_domain(name: &str, tenant: &str) -> Result<Domain, virt::error::Error> {
+        let conn = Connect::open(Some("qemu:///system"))?;
+        Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, name))
+    }
+
+    async fn post_to_agent(target: &str, path: &str, query: &serde_json::Value, token: &str) -> Result<String, io::Error> {
+        let response = Client::new().post(format!("http://{}:3000{}", target, path))
+            .header("Content-Type", "application/json")
+            .header("X-AWP-Agent-Token", token)
+            .body(query.to_string())
+            .send()
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to connect to hypervisor {}: {}", target, e)))?;
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(io::Error::other(format!("Hypervisor {} returned {}: {}", target, status, body)));
+        }
+
+        Ok(body)
+    }
+
+    fn list_files(name: &str) -> Result<Vec<String>, io::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(VmMigration::vm_dir(name))? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    pub fn export(name: &str, tenant: &str) -> Result<VmExport, Box<dyn Error>> {
+        let domain = VmMigration::lookup_domain(name, tenant)?;
+
+        Ok(VmExport {
+            domain_xml: domain.get_xml_desc(VIR_DOMAIN_XML_INACTIVE)?,
+            files: VmMigration::list_files(name)?,
+        })
+    }
+
+    // Only files of the VM directory are handed out, whatever the path says.
+    // The disk of a running VM is copied by libvirt during live migrations.
+    pub async fn open_file(name: &str, tenant: &str, file: &str) -> Result<tokio::fs::File, Box<dyn Error>> {
+        let domain = VmMigration::lookup_domain(name, tenant)?;
+        if domain.is_active()? && file == format!("{}.qcow2", name) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::ResourceBusy, format!("VM {} must be shut off to be migrated", name))));
+        }
+
+        if !VmMigration::list_files(name)?.iter().any(|entry| entry == file) {
+            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("File {} not found for VM {}", file, name))));
+        }
+
+        Ok(tokio::fs::File::open(format!("{}/{}", VmMigration::vm_dir(name), file)).await?)
+    }
+
+    // Pulls the VM from the source hypervisor and defines it here. On shared
+    // storage the VM directory is the source copy itself and must be left
+    // alone, the source removes it once the VM is moved.
+    pub async fn import(name: String, tenant: String, source: String, image: Option<ImageSpec>) -> Result<(), Box<dyn Error>> {
+        let token = VmMigration::agent_token()?;
+
+        if VmMigration::shared_storage() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::Unsupported, "Cold migrations need local storage, the images directory is shared")));
+        }
+
+        VmMigration::create_vm_dir(&name)?;
+
+        let pulled = VmMigration::pull(&name, &tenant, &source, &token, image.as_ref()).await
+            .map_err(|e| e.to_string());
+
+        if let Err(e) = pulled {
+            if let Err(e) = VmDomain::delete_vm(name.clone(), tenant).await {
+                eprintln!("Warning: Failed to clean up partially migrated VM {}: {}", name, e);
+            }
+            return Err(Box::new(io::Error::other(e)));
+        }
+
+        Ok(())
+    }
+
+    async fn pull(name: &str, tenant: &str, source: &str, token: &str, image: Option<&ImageSpec>) -> Result<(), Box<dyn Error>> {
+        let client = Client::new();
+        let url = format!("http://{}:3000/migration/export/{}/{}", source, tenant, name);
+
+        let export = client.get(&url)
+            .header("X-AWP-Agent-Token", token)
+            .send().await?
+            .error_for_status()?
+            .text().await?;
+        let export: VmExport = serde_json::from_str(&export)?;
+
+        for file in export.files.iter() {
+            VmMigration::pull_file(&client, &url, name, file, token).await?;
+        }
+
+        // Copy-on-write disks need their base image in the local cache.
+        let disk = VmMigration::vm_disk(name);
+        if BaseImage::backing_file(&disk)?.is_some() {
+            let image = image.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Disk of VM {} has a backing file but no image was given", name)))?;
+            BaseImage::ensure(image).await?;
+            BaseImage::protect(&image.file)?;
+        }
+
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::define_xml(&conn, &export.domain_xml)?;
+        domain.set_autostart(true)?;
+
+        // The interface id stays the same, ovn-controller on this chassis
+        // claims the logical switch port once the port shows up.
+        ovs::OvsDbRequest::add_port(name, None, tenant.to_string()).await?;
+        Ok(())
+    }
+
+    async fn pull_file(client: &Client, url: &str, name: &str, file: &str, token: &str) -> Result<(), Box<dyn Error>> {
+        let path = format!("{}/{}", VmMigration::vm_dir(name), file);
+        let partial = format!("{}.part", path);
+
+        let mut response = client.get(format!("{}/{}", url, file))
+            .header("X-AWP-Agent-Token", token)
+            .send().await?
+            .error_for_status()?;
+
+        let mut output = tokio::fs::File::create(&partial).await?;
+        while let Some(chunk) = response.chunk().await? {
+            output.write_all(&chunk).await?;
+        }
+        output.flush().await?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    // Gets the target ready for an incoming live migration. On shared storage
+    // the disk is already there, otherwise the seed is pulled from the source
+    // and an empty disk of the same size is created for libvirt to copy into.
+    // Returns whether the storage is shared.
+    pub async fn prepare(name: String, tenant: String, source: String, disk_size: u64, image: Option<ImageSpec>) -> Result<bool, Box<dyn Error>> {
+        let token = VmMigration::agent_token()?;
+
+        if VmMigration::shared_storage() {
+            if fs::metadata(VmMigration::vm_disk(&name)).is_err() {
+                return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Disk of VM {} not found on shared storage", name))));
+            }
+            ovs::OvsDbRequest::add_port(&name, None, tenant).await?;
+            return Ok(true);
+        }
+
+        VmMigration::create_vm_dir(&name)?;
+        let prepared = VmMigration::prepare_disk(&name, &tenant, &source, disk_size, image.as_ref(), &token).await
+            .map_err(|e| e.to_string());
+
+        if let Err(e) = prepared {
+            if let Err(e) = VmDomain::delete_vm(name.clone(), tenant).await {
+                eprintln!("Warning: Failed to clean up VM {} prepared for migration: {}", name, e);
+            }
+            return Err(Box::new(io::Error::other(e)));
+        }
+
+        Ok(false)
+    }
+
+    async fn prepare_disk(name: &str, tenant: &str, source: &str, disk_size: u64, image: Option<&ImageSpec>, token: &str) -> Result<(), Box<dyn Error>> {
+        let client = Client::new();
+        let url = format!("http://{}:3000/migration/export/{}/{}", source, tenant, name);
+
+        let export = client.get(&url)
+            .header("X-AWP-Agent-Token", token)
+            .send().await?
+            .error_for_status()?
+            .text().await?;
+        let export: VmExport = serde_json::from_str(&export)?;
+
+        let disk_file = format!("{}.qcow2", name);
+        for file in export.files.iter().filter(|file| **file != disk_file) {
+            VmMigration::pull_file(&client, &url, name, file, token).await?;
+        }
+
+        // Copy-on-write disks keep their base image, only the overlay is copied.
+        let size = disk_size.to_string();
+        let disk = VmMigration::vm_disk(name);
+        let mut args = vec!["create", "-f", "qcow2"];
+        let backing;
+        if let Some(image) = image {
+            BaseImage::ensure(image).await?;
+            BaseImage::protect(&image.file)?;
+            backing = BaseImage::path(&image.file);
+            args.extend(["-b", backing.as_str(), "-F", image.format.as_str()]);
+        }
+        args.extend([disk.as_str(), size.as_str()]);
+
+        let create_disk = Command::new("qemu-img").args(&args).output()?;
+        if !create_disk.status.success() {
+            return Err(Box::new(io::Error::other(format!("qemu-img create failed: {}", String::from_utf8_lossy(&create_disk.stderr)))));
+        }
+
+        ovs::OvsDbRequest::add_port(name, None, tenant.to_string()).await?;
+        Ok(())
+    }
+
+    // The migration left the VM running on the source, what was prepared for
+    // it here goes away. Shared disks belong to the source copy.
+    pub async fn abort(name: String, tenant: String, shared: bool) -> Result<(), Box<dyn Error>> {
+        if shared {
+            ovs::OvsDbRequest::delete_port(name, None, tenant).await?;
+            return Ok(());
+        }
+
+        VmDomain::delete_vm(name, tenant).await?;
+        Ok(())
+    }
+
+    pub async fn finish(name: String, tenant: String) -> Result<(), Box<dyn Error>> {
+        VmMigration::lookup_domain(&name, &tenant)?.set_autostart(true)?;
+        Ok(())
+    }
+
+    // Live migrates a running VM to the target hypervisor, copying its disk
+    // along unless the storage is shared. Bandwidth is in MiB/s. Once done
+    // the source domain is undefined and its port and directory removed.
+    pub async fn live_migrate(
+        name: String,
+        tenant: String,
+        target: String,
+        image: Option<ImageSpec>,
+        bandwidth: Option<u64>,
+        auto_converge: bool
+    ) -> Result<(), Box<dyn Error>> {
+        let token = VmMigration::agent_token()?;
+        let domain = VmMigration::lookup_domain(&name, &tenant)?;
+        if !domain.is_active()? {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("VM {} must be running to be live migrated", name))));
+        }
+
+        let disk = VmMigration::vm_disk(&name);
+        let copy_on_write = BaseImage::backing_file(&disk)?.is_some();
+        let prepare_query = serde_json::json!({
+            "name": name,
+            "tenant": tenant,
+            "source": gethostname().to_string_lossy(),
+            "disk_size": VmDomain::disk_virtual_size(&disk)?,
+            "image": if copy_on_write { image } else { None },
+        });
+
+        let shared = VmMigration::post_to_agent(&target, "/migration/prepare", &prepare_query, &token).await? == "shared";
+
+        let mut flags = VIR_MIGRATE_LIVE | VIR_MIGRATE_PEER2PEER | VIR_MIGRATE_PERSIST_DEST | VIR_MIGRATE_UNDEFINE_SOURCE | VIR_MIGRATE_ABORT_ON_ERROR;
+        if !shared {
+            flags |= if copy_on_write { VIR_MIGRATE_NON_SHARED_INC } else { VIR_MIGRATE_NON_SHARED_DISK };
+        }
+        if auto_converge {
+            flags |= VIR_MIGRATE_AUTO_CONVERGE;
+        }
+
+        let parameters = MigrateParameters { bandwidth, ..Default::default() };
+        let uri = VmMigration::libvirt_uri(&target);
+        let migrated = tokio::task::spawn_blocking(move || domain.migrate_to_uri3(Some(&uri), parameters, flags)).await?;
+
+        let migration_query = serde_json::json!({
+            "name": name,
+            "tenant": tenant,
+            "shared": shared,
+        });
+
+        if let Err(e) = migrated {
+            if let Err(e) = VmMigration::post_to_agent(&target, "/migration/abort", &migration_query, &token).await {
+                eprintln!("Warning: Failed to clean up VM {} on {}: {}", name, target, e);
+            }
+            return Err(Box::new(e));
+        }
+
+        if let Err(e) = VmMigration::post_to_agent(&target, "/migration/finish", &migration_query, &token).await {
+            eprintln!("Warning: Failed to enable autostart of VM {} on {}: {}", name, target, e);
+        }
+
+        if let Err(e) = ovs::OvsDbRequest::delete_port(name.clone(), None, tenant).await {
+            eprintln!("Warning: Failed to delete OVS port: {:?}", e);
+        }
+        if !shared {
+            fs::remove_dir_all(VmMigration::vm_dir(&name))?;
+        }
+
+        Ok(())
+    }
+
+    // Progress of the migration running for the VM, read on the source.
+    pub fn progress(name: &str, tenant: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+        let stats = VmMigration::lookup_domain(name, tenant)?.get_job_stats(0)?;
+
+        Ok(serde_json::json!({
+            "active": stats.r#type != VIR_DOMAIN_JOB_NONE as i32,
+            "data_total": stats.data_total,
+            "data_processed": stats.data_processed,
+            "data_remaining": stats.data_remaining,
+            "mem_total": stats.mem_total,
+            "mem_remaining": stats.mem_remaining,
+            "disk_total": stats.disk_total,
+            "disk_remaining": stats.disk_remaining,
+            "time_elapsed": stats.time_elapsed,
+            "auto_converge_throttle": stats.auto_converge_throttle,
+        }))
+    }
+}
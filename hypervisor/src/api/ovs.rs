@@ -3,9 +3,39 @@
 
 use std::io;
 use serde::{Serialize, Deserialize};
-use std::process::Command;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
 
 
+static OVSDB_SOCKET: &str = "/var/run/openvswitch/db.sock";
+static AWP_OWNER: &str = "awp";
+static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Deserialize, Debug)]
+struct Config {
+    ovn: Option<OvnConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OvnConfig {
+    bridge_mappings: Vec<BridgeMapping>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BridgeMapping {
+    physnet: String,
+    bridge: String,
+    uplink: Option<String>,
+}
+
+fn read_conf_file(config_file: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let file = std::fs::read_to_string(config_file)?;
+    let config: Config = serde_yaml::from_str(&file)?;
+    Ok(config)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct OvsDbRequest {
     method: String,
@@ -14,85 +44,316 @@ pub struct OvsDbRequest {
 }
 
 impl OvsDbRequest {
-    pub async fn add_vnet_to_provider_bridge(port_name: &str, provider_bridge: &str, tenant: &str) -> Result<(), io::Error> {
-        let vnet_name = tenant.to_string() + "-" + port_name;
-
-        let add_vnet = Command::new("ovs-vsctl")
-            .args(&[
-                "add-port",
-                provider_bridge,
-                &vnet_name
-            ])
-            .output()?;
-
-        if !add_vnet.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ovs-vsctl add-port: {}", String::from_utf8_lossy(&add_vnet.stderr)),
-            ));
+    fn transact(operations: Vec<Value>, comment: String) -> Self {
+        let mut params = vec![json!("Open_vSwitch")];
+        params.extend(operations);
+        params.push(json!({"op": "comment", "comment": comment}));
+
+        OvsDbRequest {
+            method: "transact".to_string(),
+            params,
+            id: REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    async fn send(&self) -> Result<Vec<Value>, io::Error> {
+        let mut stream = UnixStream::connect(OVSDB_SOCKET).await?;
+
+        let mut request = serde_json::to_vec(self)?;
+        request.push(b'\n');
+        stream.write_all(&request).await?;
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 4096];
+
+        loop {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete response from ovsdb-server"));
+            }
+            response.extend_from_slice(&buffer[..n]);
+
+            match serde_json::from_slice::<Value>(&response) {
+                Ok(json) => return Self::parse_response(json),
+                Err(e) if e.is_eof() => continue,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            }
+        }
+    }
+
+    // A transaction is atomic, if any operation fails ovsdb-server reports
+    // its error in the matching result slot and nothing is committed.
+    fn parse_response(response: Value) -> Result<Vec<Value>, io::Error> {
+        if !response["error"].is_null() {
+            return Err(io::Error::other(format!("ovsdb-server error: {}", response["error"])));
+        }
+
+        let results = match response["result"].as_array() {
+            Some(results) => results.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing result in ovsdb-server response")),
+        };
+
+        if let Some(failed) = results.iter().find(|result| !result["error"].is_null()) {
+            return Err(io::Error::other(format!("ovsdb transaction failed: {} ({})", failed["error"], failed["details"])));
+        }
+
+        Ok(results)
+    }
+
+    async fn select(table: &str, conditions: Value, columns: &[&str]) -> Result<Vec<Value>, io::Error> {
+        let request = OvsDbRequest::transact(vec![json!({
+            "op": "select",
+            "table": table,
+            "where": conditions,
+            "columns": columns,
+        })], format!("awp select on {}", table));
+
+        let results = request.send().await?;
+        Ok(results[0]["rows"].as_array().cloned().unwrap_or_default())
+    }
+
+    async fn find_uuid(table: &str, name: &str) -> Result<Option<String>, io::Error> {
+        let rows = OvsDbRequest::select(table, json!([["name", "==", name]]), &["_uuid"]).await?;
+        Ok(rows.first().and_then(|row| row["_uuid"][1].as_str()).map(|uuid| uuid.to_string()))
+    }
+
+    // Inserts Interface and Port in the same transaction that plugs the port
+    // into the bridge, the wait operations make it fail cleanly if the bridge
+    // is missing or the port has been added concurrently. Only ports tagged
+    // with the AWP owner are garbage collected.
+    async fn insert_port(bridge_name: &str, port_name: &str, interface_type: &str, external_ids: Value, port_external_ids: Value) -> Result<(), io::Error> {
+        let request = OvsDbRequest::transact(vec![
+            json!({
+                "op": "wait",
+                "table": "Bridge",
+                "where": [["name", "==", bridge_name]],
+                "columns": ["name"],
+                "until": "==",
+                "rows": [{"name": bridge_name}],
+                "timeout": 0
+            }),
+            json!({
+                "op": "wait",
+                "table": "Port",
+                "where": [["name", "==", port_name]],
+                "columns": ["name"],
+                "until": "==",
+                "rows": [],
+                "timeout": 0
+            }),
+            json!({
+                "op": "insert",
+                "table": "Interface",
+                "row": {
+                    "name": port_name,
+                    "type": interface_type,
+                    "external_ids": external_ids
+                },
+                "uuid-name": "iface"
+            }),
+            json!({
+                "op": "insert",
+                "table": "Port",
+                "row": {
+                    "name": port_name,
+                    "interfaces": ["named-uuid", "iface"],
+                    "external_ids": port_external_ids
+                },
+                "uuid-name": "port"
+            }),
+            json!({
+                "op": "mutate",
+                "table": "Bridge",
+                "where": [["name", "==", bridge_name]],
+                "mutations": [["ports", "insert", ["set", [["named-uuid", "port"]]]]]
+            }),
+        ], format!("awp: add port {} to {}", port_name, bridge_name));
+
+        request.send().await?;
+        Ok(())
+    }
+
+    async fn ensure_bridge(bridge_name: &str) -> Result<(), io::Error> {
+        if OvsDbRequest::find_uuid("Bridge", bridge_name).await?.is_some() {
+            return Ok(());
         }
 
+        let request = OvsDbRequest::transact(vec![
+            json!({
+                "op": "insert",
+                "table": "Interface",
+                "row": {"name": bridge_name, "type": "internal"},
+                "uuid-name": "iface"
+            }),
+            json!({
+                "op": "insert",
+                "table": "Port",
+                "row": {"name": bridge_name, "interfaces": ["named-uuid", "iface"]},
+                "uuid-name": "port"
+            }),
+            json!({
+                "op": "insert",
+                "table": "Bridge",
+                "row": {"name": bridge_name, "ports": ["named-uuid", "port"]},
+                "uuid-name": "bridge"
+            }),
+            json!({
+                "op": "mutate",
+                "table": "Open_vSwitch",
+                "where": [],
+                "mutations": [["bridges", "insert", ["set", [["named-uuid", "bridge"]]]]]
+            }),
+        ], format!("awp: add bridge {}", bridge_name));
+
+        request.send().await?;
+        Ok(())
+    }
+
+    // Provider networks are OVN localnet ports, ovn-controller patches br-int
+    // to the bridge mapped to their physnet, so each chassis needs just one
+    // provider bridge instead of one per VLAN.
+    pub async fn set_bridge_mappings() -> Result<(), io::Error> {
+        let config = read_conf_file("config.yaml")
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Failed to read config file: {}", e)))?;
+
+        let mappings = match config.ovn {
+            Some(ovn) if !ovn.bridge_mappings.is_empty() => ovn.bridge_mappings,
+            _ => return Ok(()),
+        };
+
+        for mapping in &mappings {
+            OvsDbRequest::ensure_bridge(&mapping.bridge).await?;
+            if let Some(uplink) = &mapping.uplink {
+                if OvsDbRequest::find_uuid("Port", uplink).await?.is_none() {
+                    OvsDbRequest::insert_port(&mapping.bridge, uplink, "", json!(["map", []]), json!(["map", []])).await?;
+                } else {
+                    // Uplinks added by earlier releases carry the owner tag.
+                    let request = OvsDbRequest::transact(vec![json!({
+                        "op": "mutate",
+                        "table": "Port",
+                        "where": [["name", "==", uplink]],
+                        "mutations": [["external_ids", "delete", ["set", ["awp-owner"]]]]
+                    })], format!("awp: untag uplink {}", uplink));
+
+                    request.send().await?;
+                }
+            }
+        }
+
+        let bridge_mappings = mappings.iter()
+            .map(|mapping| format!("{}:{}", mapping.physnet, mapping.bridge))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        let request = OvsDbRequest::transact(vec![json!({
+            "op": "mutate",
+            "table": "Open_vSwitch",
+            "where": [],
+            "mutations": [
+                ["external_ids", "delete", ["set", ["ovn-bridge-mappings"]]],
+                ["external_ids", "insert", ["map", [["ovn-bridge-mappings", &bridge_mappings]]]]
+            ]
+        })], format!("awp: set ovn-bridge-mappings={}", bridge_mappings));
+
+        request.send().await?;
         Ok(())
     }
 
     pub async fn add_port(port_name: &str, bridge_name: Option<String>, tenant: String) -> Result<(), io::Error> {
         let bridge_name = bridge_name.unwrap_or("br-int".to_string());
         let port_name = tenant + "-" + port_name;
+        let external_ids = json!(["map", [["iface-id", &port_name], ["awp-owner", AWP_OWNER]]]);
 
-        let add_port = Command::new("ovs-vsctl")
-            .args(&[
-                "add-port",
-                &bridge_name,
-                &port_name,
-                &format!("external_ids:iface-id={}", &port_name),
-            ])
-            .output()?;
-
-        if !add_port.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ovs-vsctl add-port: {}", String::from_utf8_lossy(&add_port.stderr)),
-            ));
-        }
+        // Re-adding an existing port only refreshes the OVN binding so that
+        // retries after a partial failure converge instead of erroring out.
+        if OvsDbRequest::find_uuid("Port", &port_name).await?.is_some() {
+            let request = OvsDbRequest::transact(vec![json!({
+                "op": "update",
+                "table": "Interface",
+                "where": [["name", "==", &port_name]],
+                "row": {"external_ids": external_ids}
+            })], format!("awp: refresh port {}", port_name));
 
-        let set_interface = Command::new("ovs-vsctl")
-            .args(&[
-                "set",
-                "Interface",
-                &port_name,
-                &format!("external_ids:iface-id={}", &port_name),
-            ])
-            .output()?;
-
-        if !set_interface.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ovs-vsctl set Interface failure: {}", String::from_utf8_lossy(&set_interface.stderr)),
-            ));
+            request.send().await?;
+            return Ok(());
         }
 
-        Ok(())
+        OvsDbRequest::insert_port(&bridge_name, &port_name, "", external_ids, json!(["map", [["awp-owner", AWP_OWNER]]])).await
     }
 
     pub async fn delete_port(port_name: String, bridge_name: Option<String>, tenant: String) -> Result<(), io::Error> {
         let bridge_name = bridge_name.unwrap_or("br-int".to_string());
         let port_name = tenant + "-" + &port_name;
 
-        let remove_port = Command::new("ovs-vsctl")
-            .args(&[
-                "del-port",
-                &bridge_name,
-                &port_name,
-            ])
-            .output()?;
-
-        if !remove_port.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ovs-vsctl del-port failed with: {}", String::from_utf8_lossy(&remove_port.stderr)),
-            ));
-        }
+        OvsDbRequest::remove_port(&bridge_name, &port_name).await
+    }
+
+    async fn remove_port(bridge_name: &str, port_name: &str) -> Result<(), io::Error> {
+        let port_uuid = match OvsDbRequest::find_uuid("Port", port_name).await? {
+            Some(port_uuid) => port_uuid,
+            None => return Ok(()),
+        };
 
+        // Port and Interface are not root tables, dropping the reference from
+        // the bridge garbage collects both rows.
+        let request = OvsDbRequest::transact(vec![json!({
+            "op": "mutate",
+            "table": "Bridge",
+            "where": [["name", "==", bridge_name]],
+            "mutations": [["ports", "delete", ["set", [["uuid", &port_uuid]]]]]
+        })], format!("awp: delete port {} from {}", port_name, bridge_name));
+
+        request.send().await?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub async fn list_awp_ports() -> Result<Vec<String>, io::Error> {
+        let rows = OvsDbRequest::select(
+            "Port",
+            json!([["external_ids", "includes", ["map", [["awp-owner", AWP_OWNER]]]]]),
+            &["name"],
+        ).await?;
+
+        Ok(rows.iter().filter_map(|row| row["name"].as_str()).map(|name| name.to_string()).collect())
+    }
+
+    // Ports are named after their libvirt domain, any AWP port without a
+    // matching domain is a leftover from a failed create or delete. Each one
+    // is removed from the bridge it is plugged into.
+    pub async fn garbage_collect_ports(domains: &[String]) -> Result<Vec<String>, io::Error> {
+        let mut removed = Vec::new();
+        let ports = OvsDbRequest::select(
+            "Port",
+            json!([["external_ids", "includes", ["map", [["awp-owner", AWP_OWNER]]]]]),
+            &["_uuid", "name"],
+        ).await?;
+        let bridges = OvsDbRequest::select("Bridge", json!([]), &["name", "ports"]).await?;
+
+        for port in &ports {
+            let (port_uuid, port_name) = match (port["_uuid"][1].as_str(), port["name"].as_str()) {
+                (Some(port_uuid), Some(port_name)) => (port_uuid, port_name.to_string()),
+                _ => continue,
+            };
+            if domains.contains(&port_name) {
+                continue;
+            }
+
+            let bridge = bridges.iter().find(|bridge| OvsDbRequest::set_contains(&bridge["ports"], port_uuid));
+            if let Some(bridge_name) = bridge.and_then(|bridge| bridge["name"].as_str()) {
+                OvsDbRequest::remove_port(bridge_name, &port_name).await?;
+                removed.push(port_name);
+            }
+        }
+
+        Ok(removed)
+    }
+
+    // OVSDB encodes single element sets as the bare element.
+    fn set_contains(set: &Value, uuid: &str) -> bool {
+        match set[0].as_str() {
+            Some("set") => set[1].as_array().is_some_and(|elements| elements.iter().any(|element| element[1] == uuid)),
+            Some("uuid") => set[1] == uuid,
+            _ => false,
+        }
+    }
+}
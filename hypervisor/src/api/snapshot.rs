@@ -0,0 +1,106 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use std::io;
+use std::error::Error;
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt::domain_snapshot::DomainSnapshot;
+use virt::sys::{
+    VIR_DOMAIN_SNAPSHOT_CREATE_ATOMIC, VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY,
+    VIR_DOMAIN_SNAPSHOT_CREATE_QUIESCE, VIR_DOMAIN_SNAPSHOT_REVERT_RUNNING
+};
+
+
+pub struct VmSnapshot {}
+
+impl VmSnapshot {
+    fn lookup_domain(tenant: &str, vm: &str) -> Result<Domain, virt::error::Error> {
+        let conn = Connect::open(Some("qemu:///system"))?;
+        Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, vm))
+    }
+
+    // Root disk and attached volumes, the cloud-init seed cdrom is read-only
+    // and left out of snapshots.
+    fn disk_targets(domain_xml: &str) -> Vec<String> {
+        domain_xml.split("<disk ")
+            .skip(1)
+            .filter(|disk| disk.starts_with("type='file' device='disk'"))
+            .filter_map(|disk| {
+                let start = disk.find("<target dev='")? + "<target dev='".len();
+                let end = disk[start..].find('\'')?;
+                Some(disk[start..start + end].to_string())
+            })
+            .collect()
+    }
+
+    // Names end up in the snapshot XML, anything beyond this charset could
+    // inject elements such as overlay paths.
+    fn check_name(name: &str) -> Result<(), io::Error> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid snapshot name: {}", name)));
+        }
+
+        Ok(())
+    }
+
+    pub async fn create_snapshot(name: String, tenant: String, vm: String, kind: String, quiesce: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        VmSnapshot::check_name(&name)?;
+        let domain = VmSnapshot::lookup_domain(&tenant, &vm)?;
+        let disks = VmSnapshot::disk_targets(&domain.get_xml_desc(0)?);
+
+        // Internal snapshots live inside the qcow2 files and also capture the
+        // memory of a running domain. External ones are disk-only, the current
+        // images become read-only backing files of new overlays.
+        let mut flags = VIR_DOMAIN_SNAPSHOT_CREATE_ATOMIC;
+        match kind.as_str() {
+            "internal" if quiesce => {
+                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Quiesce is only supported for external snapshots")));
+            },
+            "internal" => (),
+            "external" => {
+                flags |= VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY;
+                // libvirt freezes the guest filesystems through the guest
+                // agent's fsfreeze and thaws them once the overlays are in place.
+                if quiesce {
+                    flags |= VIR_DOMAIN_SNAPSHOT_CREATE_QUIESCE;
+                }
+            },
+            _ => return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid snapshot kind: {}", kind)))),
+        }
+
+        let disks_xml: String = disks.iter()
+            .map(|disk| format!("<disk name='{}' snapshot='{}'/>", disk, kind))
+            .collect();
+
+        let snapshot_xml = format!(
+            "<domainsnapshot><name>{}</name><description>awp {} snapshot</description><disks>{}</disks></domainsnapshot>",
+            name, kind, disks_xml
+        );
+
+        DomainSnapshot::create_xml(&domain, &snapshot_xml, flags)?;
+        Ok(disks)
+    }
+
+    pub async fn revert_snapshot(name: String, tenant: String, vm: String) -> Result<(), Box<dyn Error>> {
+        let domain = VmSnapshot::lookup_domain(&tenant, &vm)?;
+        let snapshot = DomainSnapshot::lookup_by_name(&domain, &name, 0)?;
+
+        // Disk-only snapshots carry no memory state and would leave the domain
+        // shut off, keep running domains running across the revert.
+        let flags = if domain.is_active()? { VIR_DOMAIN_SNAPSHOT_REVERT_RUNNING } else { 0 };
+
+        snapshot.revert(flags)?;
+        Ok(())
+    }
+
+    pub async fn delete_snapshot(name: String, tenant: String, vm: String) -> Result<(), Box<dyn Error>> {
+        let domain = VmSnapshot::lookup_domain(&tenant, &vm)?;
+        let snapshot = DomainSnapshot::lookup_by_name(&domain, &name, 0)?;
+
+        // For external snapshots libvirt commits the overlay back into its
+        // backing file before dropping the metadata.
+        snapshot.delete(0)?;
+        Ok(())
+    }
+}
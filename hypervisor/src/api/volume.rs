@@ -0,0 +1,119 @@
+// Copyright: (c) 2025, Andrea Veri <andrea.veri@gmail.com>
+// GNU General Public License v3.0+ (see COPYING or https://www.gnu.org/licenses/gpl-3.0.txt)
+
+use std::io;
+use std::fs;
+use std::error::Error;
+use std::process::Command;
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt::sys::{VIR_DOMAIN_AFFECT_CONFIG, VIR_DOMAIN_AFFECT_LIVE};
+use crate::api::libvirt::VmDomain;
+
+
+// Volumes live next to the VM directories, no VM can be named after theirs.
+static VOLUME_STORAGE_PATH: &str = "/var/lib/libvirt/images/volumes";
+pub static RESERVED_VM_NAME: &str = "volumes";
+
+pub struct VmVolume {}
+
+impl VmVolume {
+    pub fn volume_path(name: &str, tenant: &str) -> String {
+        format!("{}/{}-{}.qcow2", VOLUME_STORAGE_PATH, tenant, name)
+    }
+
+    fn check_name(name: &str) -> Result<(), io::Error> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid volume name: {}", name)));
+        }
+
+        Ok(())
+    }
+
+    pub async fn create_volume(name: String, tenant: String, size: u32) -> Result<(), io::Error> {
+        VmVolume::check_name(&name)?;
+        fs::create_dir_all(VOLUME_STORAGE_PATH)?;
+
+        let path = VmVolume::volume_path(&name, &tenant);
+        if fs::metadata(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Volume {} already exists", path)));
+        }
+
+        let create_volume = Command::new("qemu-img")
+            .args([
+                "create",
+                "-f", "qcow2",
+                &path,
+                &format!("{}G", size),
+            ])
+            .output()?;
+
+        if !create_volume.status.success() {
+            return Err(io::Error::other(
+                format!("qemu-img create failed: {}", String::from_utf8_lossy(&create_volume.stderr)),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete_volume(name: String, tenant: String) -> Result<(), io::Error> {
+        VmVolume::check_name(&name)?;
+        match fs::remove_file(VmVolume::volume_path(&name, &tenant)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn resize_volume(name: String, tenant: String, vm: Option<String>, size: u32) -> Result<(), Box<dyn Error>> {
+        VmVolume::check_name(&name)?;
+        let path = VmVolume::volume_path(&name, &tenant);
+        if fs::metadata(&path).is_err() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Volume {} not found", name))));
+        }
+
+        VmDomain::resize_disk(&tenant, vm.as_deref(), &path, size)
+    }
+
+    fn disk_xml(name: &str, tenant: &str, target_dev: &str, serial: &str) -> String {
+        format!(
+            "<disk type='file' device='disk'><driver name='qemu' type='qcow2'/><source file='{}'/><target dev='{}' bus='virtio'/><serial>{}</serial></disk>",
+            VmVolume::volume_path(name, tenant), target_dev, serial
+        )
+    }
+
+    // Running domains get the disk hotplugged, the persistent definition is
+    // updated in both cases so the volume survives a restart.
+    fn device_flags(domain: &Domain) -> Result<u32, virt::error::Error> {
+        let mut flags = VIR_DOMAIN_AFFECT_CONFIG;
+        if domain.is_active()? {
+            flags |= VIR_DOMAIN_AFFECT_LIVE;
+        }
+
+        Ok(flags)
+    }
+
+    pub async fn attach_volume(name: String, tenant: String, vm: String, target_dev: String, serial: String) -> Result<(), Box<dyn Error>> {
+        VmVolume::check_name(&name)?;
+        if fs::metadata(VmVolume::volume_path(&name, &tenant)).is_err() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, format!("Volume {} not found", name))));
+        }
+
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, vm))?;
+
+        let disk_xml = VmVolume::disk_xml(&name, &tenant, &target_dev, &serial);
+        domain.attach_device_flags(&disk_xml, VmVolume::device_flags(&domain)?)?;
+        Ok(())
+    }
+
+    pub async fn detach_volume(name: String, tenant: String, vm: String, target_dev: String, serial: String) -> Result<(), Box<dyn Error>> {
+        VmVolume::check_name(&name)?;
+        let conn = Connect::open(Some("qemu:///system"))?;
+        let domain = Domain::lookup_by_name(&conn, &format!("{}-{}", tenant, vm))?;
+
+        let disk_xml = VmVolume::disk_xml(&name, &tenant, &target_dev, &serial);
+        domain.detach_device_flags(&disk_xml, VmVolume::device_flags(&domain)?)?;
+        Ok(())
+    }
+}
@@ -72,9 +72,19 @@ async fn main() {
                 },
                 Err(err) => eprintln!("Error creating Hypervisor: {}", err),
             }
+
+            match HypervisorApi::garbage_collect_ports().await {
+                Ok(removed) if !removed.is_empty() => println!("Removed stale OVS ports: {}", removed.join(", ")),
+                Ok(_) => (),
+                Err(err) => eprintln!("{}", err),
+            }
         }
     });
 
+    if let Err(err) = HypervisorApi::setup_bridge_mappings().await {
+        eprintln!("Error configuring OVN bridge mappings: {}", err);
+    }
+
     let app = HypervisorApi::router().await;
     let server = HypervisorApi::start_server(app).await;
     match server {